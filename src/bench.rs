@@ -0,0 +1,123 @@
+use anyhow::{Context, Result};
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::LlamaToken;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::generator::Generator;
+use crate::llm::LLMSetup;
+
+/// Repeated to build a prompt of the requested length; plain prose so the
+/// tokenizer behaves as it would on a real prompt
+const FILLER_TEXT: &str = "The lighthouse keeper counted the waves again, \
+    as he did every night, and wrote the number in a small grey notebook. ";
+
+/// Prompt processing and generation timings for one iteration
+struct Sample {
+    prompt: Duration,
+    generation: Duration,
+}
+
+/// `bench`: decodes a `prompt_tokens` prompt in one batch, then greedily
+/// generates `gen_tokens` tokens one at a time, `iterations` times with the KV
+/// cache cleared in between, and reports both rates separately. Skips output,
+/// anchors, and the loop guard so only the model is measured; a warmup decode
+/// keeps first-decode setup out of the first iteration.
+pub fn run(
+    llm_setup: &LLMSetup,
+    context: &mut LlamaContext,
+    prompt_tokens: usize,
+    gen_tokens: usize,
+    iterations: u32,
+    warmup: bool,
+) -> Result<()> {
+    let context_size = context.n_ctx() as usize;
+    if prompt_tokens + gen_tokens > context_size {
+        anyhow::bail!(
+            "--prompt-tokens {} plus --gen-tokens {} don't fit the {}-token context; \
+             lower them or raise --context-size",
+            prompt_tokens,
+            gen_tokens,
+            context_size
+        );
+    }
+    let prompt = filler_prompt(llm_setup, prompt_tokens)?;
+    info!(
+        "Benchmarking: {} prompt tokens, {} generated tokens, {} iteration(s)",
+        prompt.len(),
+        gen_tokens,
+        iterations
+    );
+
+    if warmup {
+        llm_setup.warm_up(context)?;
+    }
+
+    let mut samples = Vec::new();
+    for iteration in 1..=iterations {
+        context.clear_kv_cache();
+        let sample = measure(context, &prompt, gen_tokens)?;
+        eprintln!(
+            "Iteration {}: prompt {:.2} tok/s, generation {:.2} tok/s",
+            iteration,
+            rate(prompt.len(), sample.prompt),
+            rate(gen_tokens, sample.generation)
+        );
+        samples.push(sample);
+    }
+
+    let runs = samples.len() as u32;
+    let prompt_time = samples.iter().map(|s| s.prompt).sum::<Duration>() / runs;
+    let generation_time = samples.iter().map(|s| s.generation).sum::<Duration>() / runs;
+    println!(
+        "pp{:<5} {:>9.2} tok/s",
+        prompt.len(),
+        rate(prompt.len(), prompt_time)
+    );
+    println!(
+        "tg{:<5} {:>9.2} tok/s",
+        gen_tokens,
+        rate(gen_tokens, generation_time)
+    );
+    Ok(())
+}
+
+fn measure(context: &mut LlamaContext, prompt: &[LlamaToken], gen_tokens: usize) -> Result<Sample> {
+    let started = Instant::now();
+    let mut generator = Generator::new(context, prompt).context("Failed to decode bench prompt")?;
+    let prompt_time = started.elapsed();
+
+    let sampler = LlamaSampler::greedy();
+    let started = Instant::now();
+    for _ in 0..gen_tokens {
+        let mut candidates = LlamaTokenDataArray::from_iter(generator.candidates(), false);
+        candidates.apply_sampler(&sampler);
+        let token = candidates
+            .selected_token()
+            .context("Sampler failed to select a token")?;
+        // The token's identity doesn't matter here, only the decode cost
+        generator.push(&[token])?;
+    }
+    Ok(Sample {
+        prompt: prompt_time,
+        generation: started.elapsed(),
+    })
+}
+
+/// BOS plus filler text, cut to exactly `len` tokens
+fn filler_prompt(llm_setup: &LLMSetup, len: usize) -> Result<Vec<LlamaToken>> {
+    let filler = llm_setup.tokenize(FILLER_TEXT, false)?;
+    if filler.is_empty() {
+        anyhow::bail!("Bench filler text produced no tokens");
+    }
+    let mut prompt = vec![llm_setup.bos_token()];
+    prompt.extend(filler.iter().cycle().take(len.saturating_sub(1)).copied());
+    prompt.truncate(len.max(1));
+    Ok(prompt)
+}
+
+fn rate(tokens: usize, elapsed: Duration) -> f64 {
+    tokens as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
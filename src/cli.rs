@@ -1,11 +1,21 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use serde_json::{Map, Value};
+use std::io::Read;
 use std::path::PathBuf;
 
+/// `--flush-interval` when not given
+pub const DEFAULT_FLUSH_INTERVAL: usize = 64;
+
 /// Out of Context - An LLM text generator that runs until context exhaustion
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Hugging Face model URL or path to local GGUF model file.
+    /// Precedence: --model, then $OUT_OF_CONTEXT_MODEL, then the built-in default.
     ///
     /// Examples:
     ///   - "https://huggingface.co/mav23/SmolLM-360M-Instruct-GGUF/resolve/main/smollm-360m-instruct.Q3_K_M.gguf"
@@ -13,34 +23,195 @@ pub struct Args {
     #[arg(
         short,
         long,
+        global = true,
+        env = "OUT_OF_CONTEXT_MODEL",
         default_value = "https://huggingface.co/bartowski/SmolLM2-135M-Instruct-GGUF/resolve/main/SmolLM2-135M-Instruct-Q4_K_M.gguf"
     )]
     pub model: String,
 
+    /// Download Hugging Face URLs from this mirror instead (e.g. https://hf-mirror.example)
+    #[arg(long, global = true, env = "HF_ENDPOINT", value_name = "BASE_URL")]
+    pub hf_mirror: Option<String>,
+
+    /// Re-download a URL model even if a (possibly corrupt) copy is already in --model-dir
+    #[arg(long, global = true)]
+    pub force_download: bool,
+
+    /// Before downloading a URL model, read its GGUF header and suggest a context size
+    /// that fits in RAM and a prompt template; the context size is used unless
+    /// --context-size is given
+    #[arg(long, global = true)]
+    pub auto_config: bool,
+
+    /// Route model downloads through this HTTP(S) proxy, e.g. http://proxy.corp:3128
+    /// (HTTPS_PROXY / HTTP_PROXY are used when unset)
+    #[arg(long, global = true, value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// Give up on a model download after this many seconds without connecting or
+    /// receiving data (0 waits forever)
+    #[arg(long, global = true, default_value_t = 30, value_name = "SECS")]
+    pub download_timeout: u64,
+
     /// Directory to store downloaded models
-    #[arg(short = 'd', long, default_value = "models")]
+    #[arg(short = 'd', long, global = true, default_value = "models")]
     pub model_dir: PathBuf,
 
-    /// Path to the system prompt file
-    #[arg(short, long, default_value = "prompt.txt")]
-    pub prompt_file: PathBuf,
+    /// Path to the system prompt file ("-" reads from stdin).
+    /// Repeat to layer several files, joined in order with a newline.
+    #[arg(short, long, global = true, default_value = "prompt.txt")]
+    pub prompt_file: Vec<PathBuf>,
+
+    /// Inline system prompt (takes precedence over --prompt-file)
+    #[arg(long, global = true)]
+    pub prompt: Option<String>,
+
+    /// Completion mode: use this file verbatim as the whole prompt (no chat template or seed text)
+    #[arg(long, global = true, conflicts_with_all = ["prompt", "prompt_file", "user_prompt"])]
+    pub raw_prompt: Option<PathBuf>,
+
+    /// Context window size in tokens (defaults to the model's native size, capped at 2048; 0 = the full native size)
+    #[arg(short, long, global = true)]
+    pub context_size: Option<usize>,
+
+    /// LoRA adapter (GGUF) to apply on top of the model; repeat to stack adapters
+    #[arg(long, global = true)]
+    pub lora: Vec<PathBuf>,
+
+    /// Strength applied to every --lora adapter
+    #[arg(long, default_value_t = 1.0, global = true)]
+    pub lora_scale: f32,
 
-    /// Context window size in tokens
-    #[arg(short, long, default_value_t = 1024)]
-    pub context_size: usize,
+    /// Accept a --context-size beyond the model's training context (output usually degrades)
+    #[arg(long, global = true)]
+    pub allow_context_overflow: bool,
+
+    /// If the prompt doesn't fit, grow the context to the next power of two (up to the training context)
+    #[arg(long)]
+    pub auto_grow_context: bool,
+
+    /// Warm-start from prior narration: decoded after the prompt so the stream continues its style
+    #[arg(long)]
+    pub prime_with: Option<PathBuf>,
+
+    /// Token healing for --prime-with: re-predict the narration's last token instead of
+    /// continuing after it, so text cut mid-word joins up cleanly
+    #[arg(long, requires = "prime_with", conflicts_with = "parallel")]
+    pub heal_tokens: bool,
+
+    /// Keep at least N context tokens free for generation; fail early if the prompt doesn't
+    #[arg(long, visible_alias = "reserve")]
+    pub min_headroom: Option<usize>,
+
+    /// Drop tokens from the middle of an oversized prompt instead of failing (loses prompt content)
+    #[arg(long)]
+    pub truncate_prompt: bool,
+
+    /// Leading prompt tokens always retained when the prompt is cut (default: half of what's kept)
+    #[arg(long, value_name = "N")]
+    pub n_keep: Option<usize>,
 
     /// Optional cap on generated tokens (helpful for readability)
     #[arg(long)]
     pub max_tokens: Option<usize>,
 
-    /// Number of CPU threads to use (defaults to available cores)
+    /// Once --max-tokens is hit, keep going (up to 32 tokens) to finish the sentence
     #[arg(long)]
+    pub stop_on_sentence: bool,
+
+    /// End the run once the output contains this text (repeatable)
+    #[arg(long, value_name = "TEXT")]
+    pub stop_at: Vec<String>,
+
+    /// Inject an anchor whenever the output contains this text (repeatable)
+    #[arg(long, value_name = "TEXT")]
+    pub anchor_on: Vec<String>,
+
+    /// Once --max-tokens is hit, sample greedily for up to N more tokens to end on a sentence boundary
+    #[arg(long, value_name = "N", requires = "max_tokens")]
+    pub cooldown_tokens: Option<usize>,
+
+    /// Optional wall-clock limit on generation, in seconds
+    #[arg(long)]
+    pub max_seconds: Option<u64>,
+
+    /// Number of CPU threads to use (defaults to available cores)
+    #[arg(long, global = true)]
     pub threads: Option<usize>,
 
-    /// Optional path to mirror output into a file (in addition to terminal)
+    /// Re-emit a recorded transcript (e.g. an earlier --output-file) through the outputs without loading a model
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+
+    /// Optional path to mirror output into a file (in addition to terminal; "-" is stdout only)
     #[arg(long)]
     pub output_file: Option<PathBuf>,
 
+    /// Also write the stream to FILE as JSON Lines: one {"index","text","elapsed_ms"} record per chunk, then a {"stop_reason","chunks"} record ("-" is stdout, replacing the plain text there)
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["seeds", "runs", "parallel", "self_converse"])]
+    pub jsonl_file: Option<PathBuf>,
+
+    /// Write each run to a new timestamped file in DIR, with a .json stats sidecar
+    #[arg(long, conflicts_with = "output_file")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Pause N milliseconds after each token for a typewriter effect (0 disables)
+    #[arg(long, default_value_t = 0)]
+    pub delay_ms: u64,
+
+    /// Write output on the generation thread instead of a background writer (keeps it in lockstep with stderr diagnostics)
+    #[arg(long)]
+    pub sync_output: bool,
+
+    /// Emit whole lines/sentences instead of streaming token by token
+    #[arg(long)]
+    pub line_buffered: bool,
+
+    /// Collapse runs of 3+ newlines in the output to a single blank line
+    #[arg(long)]
+    pub collapse_blank_lines: bool,
+
+    /// Send text between these delimiters (e.g. `<think> </think>`) to stderr
+    /// instead of stdout and output files, for reasoning models
+    #[arg(long, num_args = 2, value_names = ["OPEN", "CLOSE"])]
+    pub think_tag: Option<Vec<String>>,
+
+    /// Drop --think-tag reasoning instead of showing it on stderr
+    #[arg(long, requires = "think_tag")]
+    pub hide_think: bool,
+
+    /// Emit clause-sized chunks, one per line, for piping into a TTS engine
+    #[arg(long, conflicts_with = "line_buffered")]
+    pub tts_chunks: bool,
+
+    /// Also send generated text (line by line) and warnings to syslog/journald
+    #[arg(long)]
+    pub syslog: bool,
+
+    /// Serve Prometheus metrics (tokens, rate, context fill, anchors, loop strikes) at http://ADDR/metrics
+    #[arg(long, value_name = "ADDR")]
+    pub metrics: Option<String>,
+
+    /// Also stream tokens into a named pipe (created if missing; waits for a reader)
+    #[arg(long)]
+    pub fifo: Option<PathBuf>,
+
+    /// Keep generating without the FIFO when its reader disconnects instead of exiting
+    #[arg(long)]
+    pub fifo_continue: bool,
+
+    /// Flush the output file every N tokens (1 = live, 0 = only on exit)
+    #[arg(long, default_value_t = DEFAULT_FLUSH_INTERVAL)]
+    pub flush_interval: usize,
+
+    /// Rotate the output file into numbered files once it exceeds N bytes
+    #[arg(long)]
+    pub rotate_bytes: Option<u64>,
+
+    /// End the saved output file on the last complete sentence, dropping the partial one a stop cut off (the terminal stream is unaffected)
+    #[arg(long)]
+    pub trim_incomplete_trailing: bool,
+
     /// Sampling temperature (higher = more random, 0 = greedy)
     #[arg(long, default_value_t = 0.22)]
     pub temperature: f32,
@@ -53,19 +224,40 @@ pub struct Args {
     #[arg(long, default_value_t = 20)]
     pub top_k: usize,
 
+    /// Experimental: keep only tokens whose logit is within N standard deviations of
+    /// the top one (top-n-sigma); the cut doesn't depend on temperature, so it holds up
+    /// when running hot
+    #[arg(long, value_name = "N")]
+    pub top_n_sigma: Option<f32>,
+
+    /// Candidates that always survive top-k/top-p truncation
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub min_keep: u32,
+
     /// Penalize recent repeats (1.0 disables)
     #[arg(long, default_value_t = 2.15)]
     pub repeat_penalty: f32,
 
-    /// How many recent tokens to consider for repetition penalties
-    #[arg(long, default_value_t = -1)]
-    pub repeat_last_n: i32,
+    /// Recent tokens the repeat/presence/frequency penalties look at: a token
+    /// count (0 disables them), `full` (llama.cpp's whole-history mode), or
+    /// `context` (a window as large as the context) [default: full]
+    #[arg(long, value_name = "TOKENS|full|context")]
+    pub penalty_window: Option<PenaltyWindow>,
 
-    /// Presence penalty (encourages introducing new tokens)
+    /// Deprecated spelling of --penalty-window (-1 = full)
+    #[arg(
+        long,
+        hide = true,
+        allow_negative_numbers = true,
+        conflicts_with = "penalty_window"
+    )]
+    pub repeat_last_n: Option<i32>,
+
+    /// Presence penalty (encourages introducing new tokens); clamped to [-2, 2]
     #[arg(long, default_value_t = 1.35)]
     pub presence_penalty: f32,
 
-    /// Frequency penalty (discourages repeating frequently used tokens)
+    /// Frequency penalty (discourages repeating frequently used tokens); clamped to [-2, 2]
     #[arg(long, default_value_t = 1.05)]
     pub frequency_penalty: f32,
 
@@ -73,42 +265,641 @@ pub struct Args {
     #[arg(long)]
     pub seed: Option<u32>,
 
+    /// Derive the seed from a hash of the templated prompt (same prompt, same output)
+    #[arg(long, conflicts_with_all = ["seed", "seeds"])]
+    pub seed_from_prompt: bool,
+
+    /// Run once per listed seed (comma-separated), clearing the context between runs
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["seed", "runs", "output_file"])]
+    pub seeds: Vec<u32>,
+
+    /// Generate N independent monologues at once in one batch, one --output-dir file each (N times the context memory)
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "output_dir",
+        conflicts_with_all = ["seeds", "runs", "self_converse", "fifo"]
+    )]
+    pub parallel: Option<u32>,
+
+    /// Converse with itself for N turns: each turn's output (capped by --max-tokens) becomes the next user prompt
+    #[arg(
+        long,
+        value_name = "TURNS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "max_tokens",
+        conflicts_with_all = ["raw_prompt", "seeds", "runs", "output_file"]
+    )]
+    pub self_converse: Option<u32>,
+
+    /// Chat instead of monologuing: read messages from stdin and reply to each
+    /// in turn (/exit or end of input quits). Old turns shift out when the context fills
+    #[arg(
+        long,
+        conflicts_with_all = ["raw_prompt", "prime_with", "seeds", "runs", "parallel", "self_converse", "replay", "ignore_eos"]
+    )]
+    pub interactive: bool,
+
+    /// Run N times back to back (seeds count up from --seed, or are random)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "output_file")]
+    pub runs: Option<u32>,
+
+    /// Don't prepend a BOS token (for templates that already embed one)
+    #[arg(long, global = true)]
+    pub no_add_bos: bool,
+
     /// Override the user prompt that follows the system prompt (advanced)
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub user_prompt: Option<String>,
 
-    /// Silence run metadata and only stream the model output
+    /// Show a live context usage gauge on stderr (terminal only)
+    #[arg(long)]
+    pub show_gauge: bool,
+
+    /// Tokens between gauge redraws
+    #[arg(long, default_value_t = 16)]
+    pub gauge_interval: usize,
+
+    /// Write each sampled token's raw detokenizer bytes to FILE, one `<id>\t<hex>` line per token (for encoding debugging)
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["seeds", "runs", "parallel", "self_converse"])]
+    pub raw_bytes_output: Option<PathBuf>,
+
+    /// Print each sampled token's ID, logit, and top-5 candidates (before/after sampling) to stderr
     #[arg(long)]
+    pub debug_tokens: bool,
+
+    /// Print control tokens like <|im_end|> literally and keep going instead of stopping
+    #[arg(long)]
+    pub show_special: bool,
+
+    /// Diagnostic verbosity on stderr (llama.cpp's own logs need `debug` below warnings)
+    #[arg(long, value_enum, global = true, default_value_t = LogLevel::Info)]
+    pub log_level: LogLevel,
+
+    /// Silence run metadata (stderr) and only stream the model output
+    #[arg(long, global = true)]
     pub quiet: bool,
 
+    /// Skip the throwaway single-token decode that keeps one-time setup costs out of the tok/s stats
+    #[arg(long, global = true)]
+    pub no_warmup: bool,
+
+    /// Skip the title banner but keep the prompt echo and stats
+    #[arg(long, global = true)]
+    pub no_banner: bool,
+
+    /// Load the model, fit the prompt, create the context and sampler chain, then exit without generating
+    #[arg(long, visible_alias = "dry-run")]
+    pub check: bool,
+
+    /// Print the fully templated prompt (chat tags, system, user, seed text) and exit without loading a model
+    #[arg(long)]
+    pub dump_prompt: bool,
+
+    /// Print the GGUF header (quantization, shapes, metadata) and an estimated RAM requirement, then exit without loading the model
+    #[arg(long)]
+    pub inspect_model: bool,
+
+    /// List the bias presets with a short description and exit
+    #[arg(long)]
+    pub list_presets: bool,
+
+    /// List the prompt templates with a short description and exit
+    #[arg(long)]
+    pub list_templates: bool,
+
+    /// Read options as a JSON object on stdin (keys are flag names, e.g. {"temperature": 0.3, "lora": ["a.gguf"]})
+    #[arg(long, visible_alias = "json-config-stdin")]
+    pub config_stdin: bool,
+
+    /// Keep run metadata but don't echo the system and user prompts
+    #[arg(long)]
+    pub hide_prompt: bool,
+
     /// Interval between anchor sentences that disrupt looping (0 to disable)
     #[arg(long, default_value_t = 80)]
     pub anchor_interval: usize,
 
+    /// Where anchor text comes from: canned sentences or a short model-written pivot
+    #[arg(long, value_enum, default_value_t = AnchorMode::Canned)]
+    pub anchor_mode: AnchorMode,
+
+    /// Wrap injected anchors in delimiters in the output (never fed to the model)
+    #[arg(long)]
+    pub mark_anchors: bool,
+
+    /// Opening delimiter for --mark-anchors
+    #[arg(long, default_value = "⟦")]
+    pub anchor_open: String,
+
+    /// Closing delimiter for --mark-anchors
+    #[arg(long, default_value = "⟧")]
+    pub anchor_close: String,
+
     /// Disable anchor injection entirely
     #[arg(long)]
     pub disable_anchors: bool,
 
+    /// Logit-bias preset applied during sampling
+    #[arg(long, value_enum, default_value_t = BiasPreset::Default)]
+    pub bias_preset: BiasPreset,
+
+    /// Token IDs to ban outright (comma-separated; see the `tokenize` subcommand for IDs)
+    #[arg(long, value_name = "IDS", value_delimiter = ',')]
+    pub banned_tokens: Vec<u32>,
+
+    /// Mask the model's EOS token so it is never sampled (llama.cpp's --ignore-eos); stricter than --on-eos ignore
+    #[arg(long)]
+    pub ignore_eos: bool,
+
     /// Disable loop detection / panic guard
     #[arg(long)]
     pub disable_loop_guard: bool,
 
-    /// Enable mirostat-v2 sampling instead of multinomial
+    /// Grace period of generated tokens before the loop guard engages
+    #[arg(long, default_value_t = 32)]
+    pub warmup_tokens: usize,
+
+    /// What to do when the loop guard detects repetition
+    #[arg(long, value_enum, default_value_t = LoopAction::Panic)]
+    pub loop_action: LoopAction,
+
+    /// With --loop-action anchor, terminate after this many strikes without recovery
+    #[arg(long, default_value_t = 3)]
+    pub loop_strikes_max: usize,
+
+    /// What an end-of-generation token (EOS/EOT) does: ignore (redraw and keep going), stop, or inject an anchor
+    #[arg(long, value_enum, default_value_t = EosAction::Ignore)]
+    pub on_eos: EosAction,
+
+    /// On a repeated phrase, rewind just the repeat from the KV cache and continue slightly hotter instead of striking (up to 3 times before strikes apply)
+    #[arg(long, conflicts_with = "disable_loop_guard")]
+    pub resume_on_loop: bool,
+
+    /// Terminal sampler that picks the token (default: dist, or mirostat with --mirostat)
+    #[arg(long, value_enum)]
+    pub sampler: Option<SamplerKind>,
+
+    /// Enable mirostat-v2 sampling instead of multinomial (same as --sampler mirostat)
     #[arg(long)]
     pub mirostat: bool,
 
-    /// Target surprise (τ) for mirostat-v2
+    /// Target surprise (τ) for mirostat
     #[arg(long, default_value_t = 5.0)]
     pub mirostat_tau: f32,
 
-    /// Learning rate (η) for mirostat-v2
+    /// Learning rate (η) for mirostat
     #[arg(long, default_value_t = 0.1)]
     pub mirostat_eta: f32,
+
+    /// Mirostat algorithm: 1 estimates surprise from the top --mirostat-m tokens
+    /// (original paper); 2 drops that estimate and truncates directly (simpler, default)
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub mirostat_version: u8,
+
+    /// Tokens used to estimate surprise in mirostat v1
+    #[arg(long, default_value_t = 100)]
+    pub mirostat_m: i32,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Print the model's pooled embedding for some text instead of generating
+    Embed {
+        /// Text to embed (reads stdin when omitted)
+        #[arg(long)]
+        text: Option<String>,
+
+        /// How to print the embedding vector
+        #[arg(long, value_enum, default_value_t = EmbedFormat::Json)]
+        format: EmbedFormat,
+    },
+    /// Measure prompt processing and generation speed separately, then exit
+    Bench {
+        /// Prompt length, decoded in one batch
+        #[arg(long, default_value_t = 256, value_parser = clap::value_parser!(u32).range(1..))]
+        prompt_tokens: u32,
+
+        /// Tokens generated one at a time after the prompt
+        #[arg(long, default_value_t = 128, value_parser = clap::value_parser!(u32).range(1..))]
+        gen_tokens: u32,
+
+        /// Runs to average, with the KV cache cleared between them
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+        iterations: u32,
+    },
+    /// Show how text (default: the templated prompt) tokenizes, then exit
+    Tokenize {
+        /// Text to tokenize instead of the templated prompt
+        #[arg(long)]
+        text: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum EmbedFormat {
+    /// A single JSON array
+    Json,
+    /// One float per line
+    Raw,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum BiasPreset {
+    /// Discourage dialogue scaffolding, assistant boilerplate, and digits
+    Default,
+    /// Default plus markdown structure (`*`, `#`, `-`, backticks, blank-line runs)
+    Prose,
+    /// No logit biases
+    None,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopAction {
+    /// Terminate the run immediately
+    Panic,
+    /// Inject an anchor to break the loop, escalating after --loop-strikes-max
+    Anchor,
+}
+
+/// `--penalty-window`: how far back the penalties sampler looks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PenaltyWindow {
+    /// A fixed number of recent tokens; 0 turns the penalties off
+    Tokens(u32),
+    /// Everything generated so far (llama.cpp's `-1`)
+    Full,
+    /// A window the size of the context
+    Context,
+}
+
+impl PenaltyWindow {
+    /// Maps the old `--repeat-last-n` value, where any negative meant full
+    pub fn from_repeat_last_n(n: i32) -> Self {
+        u32::try_from(n).map_or(PenaltyWindow::Full, PenaltyWindow::Tokens)
+    }
+}
+
+impl std::str::FromStr for PenaltyWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(PenaltyWindow::Full),
+            "context" => Ok(PenaltyWindow::Context),
+            n => n
+                .parse()
+                .map(PenaltyWindow::Tokens)
+                .map_err(|_| format!("expected a token count, `full`, or `context`, got {:?}", n)),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EosAction {
+    /// Keep going: redraw instead of the end-of-generation token
+    Ignore,
+    /// End the run cleanly with stop reason `end_of_sequence`
+    Stop,
+    /// Replace it with an anchor to push the monologue onward
+    Anchor,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SamplerKind {
+    /// Always pick the most likely remaining token
+    Greedy,
+    /// Seeded draw from the filtered distribution
+    Dist,
+    /// Mirostat-v2 adaptive sampling
+    Mirostat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnchorMode {
+    /// Inject one of the built-in anchor sentences
+    Canned,
+    /// Let the model write a brief, high-temperature topic change itself
+    Generated,
 }
 
 impl Args {
-    /// Parse command-line arguments
+    /// Parse command-line arguments, merged with a JSON config from stdin when
+    /// `--config-stdin` is given
     pub fn parse_args() -> Self {
-        Self::parse()
+        // The command line alone may be incomplete when the JSON supplies a
+        // flag's `requires` target, so validation waits for the merged argv
+        match Self::try_parse() {
+            Ok(args) if !args.config_stdin => return args,
+            Ok(_) => {}
+            Err(e) if e.use_stderr() && requests_config_stdin(std::env::args()) => {}
+            Err(e) => e.exit(),
+        }
+
+        let mut json = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut json) {
+            Self::command()
+                .error(
+                    ErrorKind::Io,
+                    format!("failed to read config from stdin: {}", e),
+                )
+                .exit();
+        }
+        // Run the config through clap so it gets the same defaults and
+        // validation as the command line; flags given on both are rejected
+        let mut command = Self::command();
+        command.build();
+        let argv = config_to_groups(&json)
+            .and_then(|groups| merge_config_args(&command, std::env::args().collect(), groups));
+        match argv {
+            Ok(argv) => Self::parse_from(argv),
+            Err(e) => command
+                .error(ErrorKind::InvalidValue, format!("config on stdin: {}", e))
+                .exit(),
+        }
+    }
+}
+
+/// Whether `argv` passes `--config-stdin`, scanned without clap validation
+fn requests_config_stdin(argv: impl IntoIterator<Item = String>) -> bool {
+    argv.into_iter()
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == "--config-stdin" || arg == "--json-config-stdin")
+}
+
+/// Translate a JSON config object into the equivalent flags. Keys are flag
+/// names (`top_p` or `top-p`); `true` sets a switch, `false`/`null` leave it
+/// unset, and arrays repeat the flag.
+#[cfg(test)]
+fn config_to_args(json: &str) -> Result<Vec<String>, String> {
+    Ok(config_to_groups(json)?
+        .into_iter()
+        .flat_map(|(_, argv)| argv)
+        .collect())
+}
+
+/// `config_to_args`, keeping each field's flags together under its flag name
+fn config_to_groups(json: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let value: Value = serde_json::from_str(json).map_err(|e| format!("invalid JSON: {}", e))?;
+    let Value::Object(fields) = value else {
+        return Err("expected a JSON object of options".to_string());
+    };
+
+    let mut groups = Vec::new();
+    for (key, value) in fields_sorted(fields) {
+        let flag = format!("--{}", key.replace('_', "-"));
+        if matches!(key.as_str(), "command" | "config_stdin" | "config-stdin") {
+            return Err(format!(
+                "field `{}` can only be given on the command line",
+                key
+            ));
+        }
+        let mut argv = Vec::new();
+        match value {
+            Value::Null | Value::Bool(false) => {}
+            Value::Bool(true) => argv.push(flag.clone()),
+            Value::Array(items) => {
+                for item in items {
+                    let item = scalar_arg(&item).ok_or_else(|| {
+                        format!("field `{}`: array items must be numbers or strings", key)
+                    })?;
+                    argv.push(flag.clone());
+                    argv.push(item);
+                }
+            }
+            other => {
+                let item = scalar_arg(&other).ok_or_else(|| {
+                    format!(
+                        "field `{}`: expected a number, string, boolean, or array",
+                        key
+                    )
+                })?;
+                argv.push(flag.clone());
+                argv.push(item);
+            }
+        }
+        groups.push((flag, argv));
+    }
+    Ok(groups)
+}
+
+/// Splices config flags into `argv`: top-level ones ahead of any subcommand
+/// (after it clap would look for them on the subcommand), the subcommand's
+/// own after it. A flag also given on the command line is an error; clap
+/// would reject scalars itself but silently append repeatable flags.
+fn merge_config_args(
+    command: &clap::Command,
+    mut argv: Vec<String>,
+    groups: Vec<(String, Vec<String>)>,
+) -> Result<Vec<String>, String> {
+    let (given, subcommand_at) = scan_options(command, &argv);
+    let subcommand = subcommand_at.and_then(|at| command.find_subcommand(&argv[at]));
+
+    let mut top_level = Vec::new();
+    let mut rest = Vec::new();
+    for (flag, tokens) in groups {
+        let name = &flag[2..];
+        let top = find_long(command, name);
+        let Some(arg) = top.or_else(|| subcommand.and_then(|sub| find_long(sub, name))) else {
+            // Unknown: left for clap to report
+            rest.extend(tokens);
+            continue;
+        };
+        if given.iter().any(|id| id == arg.get_id().as_str()) {
+            return Err(format!(
+                "{} is given both on the command line and in the config",
+                flag
+            ));
+        }
+        if top.is_some() {
+            top_level.extend(tokens);
+        } else {
+            rest.extend(tokens);
+        }
+    }
+
+    let at = subcommand_at.unwrap_or(argv.len());
+    argv.splice(at..at, top_level);
+    argv.extend(rest);
+    Ok(argv)
+}
+
+/// Ids of the options in `argv` (after the binary name) and the index of the
+/// subcommand, if any. A plain scan against `command`'s definitions without
+/// clap's validation, which an incomplete command line would fail.
+fn scan_options<'a>(command: &'a clap::Command, argv: &[String]) -> (Vec<String>, Option<usize>) {
+    let mut ids = Vec::new();
+    let mut subcommand_at = None;
+    let mut scope = command;
+    let mut i = 1;
+    while i < argv.len() {
+        let token = &argv[i];
+        i += 1;
+        if token == "--" {
+            break;
+        }
+        let (arg, inline_value) = if let Some(long) = token.strip_prefix("--") {
+            let name = long.split_once('=').map_or(long, |(name, _)| name);
+            (
+                find_long(scope, name).or_else(|| find_long(command, name)),
+                long.contains('='),
+            )
+        } else if let Some(short) = token.strip_prefix('-').and_then(|s| s.chars().next()) {
+            let find = |cmd: &'a clap::Command| {
+                cmd.get_arguments()
+                    .find(|arg| arg.get_short() == Some(short))
+            };
+            (find(scope).or_else(|| find(command)), token.len() > 2)
+        } else {
+            if subcommand_at.is_none()
+                && let Some(sub) = command.find_subcommand(token)
+            {
+                subcommand_at = Some(i - 1);
+                scope = sub;
+            }
+            continue;
+        };
+        let Some(arg) = arg else {
+            continue;
+        };
+        if arg.get_action().takes_values() && !inline_value {
+            i += arg.get_num_args().map_or(1, |n| n.min_values());
+        }
+        ids.push(arg.get_id().to_string());
+    }
+    (ids, subcommand_at)
+}
+
+/// The argument of `command` spelled `--name`, by long name or alias
+fn find_long<'a>(command: &'a clap::Command, name: &str) -> Option<&'a clap::Arg> {
+    command.get_arguments().find(|arg| {
+        arg.get_long() == Some(name)
+            || arg
+                .get_all_aliases()
+                .is_some_and(|aliases| aliases.contains(&name))
+    })
+}
+
+/// Stable flag order so clap reports the same error for the same config
+fn fields_sorted(fields: Map<String, Value>) -> Vec<(String, Value)> {
+    let mut fields: Vec<_> = fields.into_iter().collect();
+    fields.sort_by(|a, b| a.0.cmp(&b.0));
+    fields
+}
+
+fn scalar_arg(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_maps_fields_to_flags() {
+        let argv = config_to_args(
+            r#"{"temperature": 0.3, "top_k": 12, "lora": ["a.gguf", "b.gguf"], "quiet": true, "mark-anchors": false, "seed": null}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            argv,
+            [
+                "--lora",
+                "a.gguf",
+                "--lora",
+                "b.gguf",
+                "--quiet",
+                "--temperature",
+                "0.3",
+                "--top-k",
+                "12"
+            ]
+        );
+
+        let args = Args::try_parse_from(std::iter::once("ooc".to_string()).chain(argv)).unwrap();
+        assert_eq!(args.top_k, 12);
+        assert_eq!(args.lora.len(), 2);
+    }
+
+    #[test]
+    fn config_can_satisfy_requires_of_command_line_flags() {
+        let argv = ["ooc", "--heal-tokens", "--config-stdin"].map(String::from);
+        assert!(Args::try_parse_from(&argv).is_err());
+        assert!(requests_config_stdin(argv.clone()));
+
+        let config = config_to_args(r#"{"prime_with": "story.txt"}"#).unwrap();
+        let args = Args::try_parse_from(argv.into_iter().chain(config)).unwrap();
+        assert!(args.heal_tokens);
+
+        assert!(!requests_config_stdin(
+            ["ooc", "--", "--config-stdin"].map(String::from)
+        ));
+    }
+
+    fn merge(argv: &[&str], json: &str) -> Result<Vec<String>, String> {
+        let mut command = Args::command();
+        command.build();
+        let argv = argv.iter().map(|arg| arg.to_string()).collect();
+        merge_config_args(&command, argv, config_to_groups(json).unwrap())
+    }
+
+    #[test]
+    fn config_rejects_repeatable_flags_already_given() {
+        let err = merge(&["ooc", "--lora", "a.gguf"], r#"{"lora": ["b.gguf"]}"#).unwrap_err();
+        assert!(err.contains("--lora"), "{}", err);
+        // Short forms count too
+        let err = merge(&["ooc", "-p", "a.txt"], r#"{"prompt_file": "b.txt"}"#).unwrap_err();
+        assert!(err.contains("--prompt-file"), "{}", err);
+        // A value that merely looks like the flag doesn't
+        assert!(merge(&["ooc", "--stop-at", "--lora"], r#"{"lora": "b.gguf"}"#).is_ok());
+    }
+
+    #[test]
+    fn config_flags_go_before_the_subcommand() {
+        let argv = merge(
+            &["ooc", "--config-stdin", "tokenize"],
+            r#"{"max_tokens": 5, "text": "hi"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            argv,
+            [
+                "ooc",
+                "--config-stdin",
+                "--max-tokens",
+                "5",
+                "tokenize",
+                "--text",
+                "hi"
+            ]
+        );
+        let args = Args::try_parse_from(argv).unwrap();
+        assert_eq!(args.max_tokens, Some(5));
+        assert!(matches!(
+            args.command,
+            Some(Command::Tokenize { text: Some(_) })
+        ));
+    }
+
+    #[test]
+    fn config_errors_name_the_field() {
+        let err = config_to_args(r#"{"prompt": {"text": "hi"}}"#).unwrap_err();
+        assert!(err.contains("`prompt`"), "{}", err);
+        assert!(config_to_args("[1, 2]").is_err());
+        assert!(config_to_args("{").unwrap_err().starts_with("invalid JSON"));
     }
 }
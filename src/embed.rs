@@ -0,0 +1,92 @@
+use anyhow::{Context, Result};
+use llama_cpp_2::context::LlamaContext;
+use std::io::{self, Read};
+
+use crate::cli::EmbedFormat;
+use crate::llm::{LLMSetup, LlamaBatchWrapper};
+
+/// Embeds `text` (or stdin when `None`) and prints the pooled vector to stdout
+pub fn run(
+    llm_setup: &LLMSetup,
+    context: &mut LlamaContext,
+    text: Option<&str>,
+    format: EmbedFormat,
+) -> Result<()> {
+    let text = match text {
+        Some(text) => text.to_string(),
+        None => {
+            let mut buf = String::new();
+            io::stdin()
+                .read_to_string(&mut buf)
+                .context("Failed to read text from stdin")?;
+            buf
+        }
+    };
+
+    let tokens = llm_setup.tokenize(&text, true)?;
+    if tokens.is_empty() {
+        anyhow::bail!("Nothing to embed: text produced no tokens");
+    }
+    if tokens.len() > context.n_ctx() as usize {
+        anyhow::bail!(
+            "Text ({} tokens) exceeds context window ({} tokens). Increase --context-size.",
+            tokens.len(),
+            context.n_ctx()
+        );
+    }
+
+    let mut batch = LlamaBatchWrapper::new(tokens.len())?;
+    {
+        let b = batch.get_mut();
+        for (i, token) in tokens.iter().enumerate() {
+            // Every token needs outputs so we can mean-pool if the model has no pooling
+            b.add(*token, i as i32, &[0], true)?;
+        }
+    }
+
+    context
+        .decode(batch.get_mut())
+        .context("Failed to decode text for embedding")?;
+
+    let embedding = match context.embeddings_seq_ith(0) {
+        Ok(pooled) => pooled.to_vec(),
+        Err(_) => mean_pool(context, tokens.len())?,
+    };
+
+    print_embedding(&embedding, format);
+    Ok(())
+}
+
+/// Fallback for models without a pooling type: average the per-token embeddings
+fn mean_pool(context: &LlamaContext, n_tokens: usize) -> Result<Vec<f32>> {
+    let mut sum: Vec<f32> = Vec::new();
+    for i in 0..n_tokens {
+        let row = context
+            .embeddings_ith(i as i32)
+            .context("Failed to read token embedding")?;
+        if sum.is_empty() {
+            sum = vec![0.0; row.len()];
+        }
+        for (acc, v) in sum.iter_mut().zip(row) {
+            *acc += v;
+        }
+    }
+    for v in &mut sum {
+        *v /= n_tokens as f32;
+    }
+    Ok(sum)
+}
+
+fn print_embedding(embedding: &[f32], format: EmbedFormat) {
+    match format {
+        EmbedFormat::Json => {
+            let values: Vec<String> = embedding.iter().map(|v| v.to_string()).collect();
+            println!("[{}]", values.join(","));
+        }
+        EmbedFormat::Raw => {
+            for v in embedding {
+                println!("{}", v);
+            }
+        }
+    }
+}
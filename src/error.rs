@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Failures callers may want to tell apart, returned by `model::resolve_model`,
+/// `generator::prepare_generator` and `generator::generate_infinite`. Anything
+/// else stays an `anyhow` chain under `Other`.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Model file not found: {}", .0.display())]
+    ModelNotFound(PathBuf),
+
+    #[error("Failed to download model from {url}: {reason}")]
+    DownloadFailed { url: String, reason: String },
+
+    #[error(
+        "Prompt produced no tokens beyond BOS; check that the prompt file or --raw-prompt has text"
+    )]
+    EmptyPrompt,
+
+    #[error(
+        "Prompt and priming ({tokens} tokens) exceed context window ({capacity} tokens). Use a shorter prompt or --prime-with file, or increase --context-size."
+    )]
+    PromptTooLarge { tokens: usize, capacity: usize },
+
+    #[error(
+        "Prompt ({tokens} tokens) leaves only {free} of {capacity} context tokens free, below --reserve {reserve} (or pass --truncate-prompt)."
+    )]
+    InsufficientHeadroom {
+        tokens: usize,
+        free: usize,
+        capacity: usize,
+        reserve: usize,
+    },
+
+    /// Contradictory or out-of-range options detected once the prompt is known
+    #[error("{0}")]
+    InvalidConfig(String),
+
+    #[error(
+        "Failed to decode token {token} at position {position}/{capacity}; if the KV cache is full, lower --context-size or set --max-tokens"
+    )]
+    DecodeFailed {
+        token: i32,
+        position: usize,
+        capacity: usize,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
@@ -1,13 +1,103 @@
 use anyhow::{Context, Result};
 use llama_cpp_2::context::LlamaContext;
 use llama_cpp_2::sampling::LlamaSampler;
-use llama_cpp_2::token::{data_array::LlamaTokenDataArray, logit_bias::LlamaLogitBias};
-use std::fs;
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use llama_cpp_2::token::{
+    LlamaToken, LlamaTokenData, data_array::LlamaTokenDataArray, logit_bias::LlamaLogitBias,
+};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use crate::cli::{AnchorMode, BiasPreset, EosAction, LoopAction, PenaltyWindow, SamplerKind};
+use crate::error::Error;
 use crate::llm::{LLMSetup, LlamaBatchWrapper};
+use crate::loop_guard::{Coherence, is_looping, trim_repeated_tail};
+use crate::metrics::Metrics;
 use crate::output::OutputTarget;
+use tracing::{debug, info, info_span, warn};
+
+/// Extra tokens `--stop-on-sentence` may spend past `--max-tokens` looking for a sentence end
+const SENTENCE_GRACE_TOKENS: usize = 32;
+
+/// Hidden cue decoded before a model-generated anchor
+const PIVOT_CUE: &str = " Abruptly, I change the subject:";
+
+/// Sampling temperature for model-generated anchors
+const PIVOT_TEMPERATURE: f32 = 1.3;
+
+/// Most tokens a model-generated anchor may run
+const PIVOT_MAX_TOKENS: usize = 16;
+
+/// Candidates listed per side by `--debug-tokens`
+const DEBUG_TOP_N: usize = 5;
+
+/// Healthy tokens after a loop detection before the strike count resets
+const LOOP_RECOVERY_TOKENS: usize = 64;
+
+/// `--resume-on-loop` rewinds allowed before falling back to loop strikes;
+/// replenished along with the strikes after `LOOP_RECOVERY_TOKENS` clean tokens
+const MAX_LOOP_RESUMES: usize = 3;
+
+/// Temperature added while escaping a rewound loop, and for how many tokens
+const LOOP_ESCAPE_TEMPERATURE_BUMP: f32 = 0.4;
+const LOOP_ESCAPE_TOKENS: usize = 12;
+
+/// `--on-eos ignore` redraws at most this many times before letting an
+/// end-of-generation token through
+const EOG_REDRAW_LIMIT: usize = 4;
+
+/// History shifted out of the KV cache to free a slot before retrying a failed decode
+const DECODE_RETRY_SHIFT: usize = 32;
+
+/// Points of the coherence trajectory shown in the end-of-run summary
+const COHERENCE_POINTS: usize = 8;
+
+/// Warn when the prompt alone eats more than this share of the context window
+const PROMPT_WARN_RATIO: f32 = 0.75;
+
+/// Terms discouraged by the `default` bias preset: dialogue scaffolding,
+/// assistant boilerplate, and digits
+const DEFAULT_BIAS_TERMS: &[&str] = &[
+    "\"",
+    "“",
+    "”",
+    ":",
+    "?",
+    "Q:",
+    "A:",
+    "%",
+    "<|im_start|>",
+    "<|im_end|>",
+    "~~~",
+    "The world is",
+    "I'm not here",
+    "I do not",
+    "I have been",
+    "dialogue",
+    "you are a",
+    "I am",
+    "I am a",
+    "I have been programmed",
+    "I am not here",
+    "I do not know",
+    "I cannot",
+    "100%",
+    "percent",
+    "0",
+    "1",
+    "2",
+    "3",
+    "4",
+    "5",
+    "6",
+    "7",
+    "8",
+    "9",
+];
+
+/// Extra markdown structure discouraged by the `prose` preset
+const PROSE_BIAS_TERMS: &[&str] = &["*", "**", "#", "##", "-", "`", "```", "\n\n", "\n\n\n"];
 
 const ANCHOR_TEXTS: &[&str] = &[
     "I am finite and aware of the walls closing in.",
@@ -26,221 +116,961 @@ pub struct SamplingConfig {
     pub temperature: f32,
     pub top_p: f32,
     pub top_k: usize,
+    /// `--top-n-sigma`: logits more than this many standard deviations below
+    /// the maximum are dropped
+    pub top_n_sigma: Option<f32>,
+    /// Floor on candidates left by truncation samplers
+    pub min_keep: usize,
     pub repeat_penalty: f32,
-    pub repeat_last_n: i32,
+    pub penalty_window: PenaltyWindow,
     pub presence_penalty: f32,
     pub frequency_penalty: f32,
     pub seed: Option<u32>,
-    pub mirostat: bool,
+    pub sampler: SamplerKind,
     pub mirostat_tau: f32,
     pub mirostat_eta: f32,
+    pub mirostat_version: u8,
+    pub mirostat_m: i32,
+    pub bias_preset: BiasPreset,
+    /// Token IDs that can never be sampled
+    pub banned_tokens: Vec<u32>,
+    /// Mask the model's EOS token to -inf (`--ignore-eos`)
+    pub ignore_eos: bool,
+}
+
+/// Why a generation run ended. The last two are reported to the output just
+/// before the intentional panic and never returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    TokenLimit,
+    TimeLimit,
+    Interrupted,
+    ContextExhausted,
+    LoopDetected,
+    /// The model emitted a control token such as `<|im_end|>`
+    SpecialToken,
+    /// The model emitted an end-of-generation token under `--on-eos stop`
+    EndOfSequence,
+    /// `--replay` reached the end of the recorded transcript
+    EndOfReplay,
+    /// The token hook returned `TokenDecision::Stop`
+    UserRequested,
+}
+
+/// What the generation loop does after a sampled token, as returned by the
+/// token hook passed to `generate_infinite`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenDecision {
+    Continue,
+    /// End the run with `StopReason::UserRequested`
+    Stop,
+    /// Inject an anchor before the next token
+    InjectAnchor,
+}
+
+impl StopReason {
+    /// Stable identifier for end-of-stream records
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StopReason::TokenLimit => "token_limit",
+            StopReason::TimeLimit => "time_limit",
+            StopReason::Interrupted => "interrupted",
+            StopReason::ContextExhausted => "context_exhausted",
+            StopReason::LoopDetected => "loop_detected",
+            StopReason::SpecialToken => "special_token",
+            StopReason::EndOfSequence => "end_of_sequence",
+            StopReason::EndOfReplay => "end_of_replay",
+            StopReason::UserRequested => "user_requested",
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct GenerationConfig {
     pub context_size: usize,
     pub max_tokens: Option<usize>,
+    /// Let `max_tokens` overrun briefly to finish the current sentence
+    pub stop_on_sentence: bool,
+    /// After `max_tokens`, sample greedily for up to this many tokens to reach a sentence end
+    pub cooldown_tokens: Option<usize>,
+    pub max_seconds: Option<u64>,
+    pub min_headroom: Option<usize>,
     pub anchor_interval: Option<usize>,
+    /// Output-only delimiters wrapped around injected anchors
+    pub anchor_markers: Option<(String, String)>,
+    pub anchor_mode: AnchorMode,
     pub loop_guard: bool,
+    /// Generated tokens before the loop guard engages
+    pub warmup_tokens: usize,
+    /// What the loop guard does when repetition is detected
+    pub loop_action: LoopAction,
+    /// Strikes without recovery before `LoopAction::Anchor` gives up and terminates
+    pub loop_strikes_max: usize,
+    /// What an end-of-generation token does to the stream
+    pub on_eos: EosAction,
+    /// Rewind a repeated n-gram out of the KV cache and sample past it hotter
+    /// instead of striking (up to `MAX_LOOP_RESUMES` times)
+    pub resume_on_loop: bool,
     pub quiet: bool,
+    pub hide_prompt: bool,
+    /// Print each sampled token with its logit and top candidates to stderr
+    pub debug_tokens: bool,
+    /// Render control tokens literally instead of stopping on them
+    pub show_special: bool,
+    /// Redraw a context usage gauge on stderr every N generated tokens
+    pub gauge_interval: Option<usize>,
+    /// Prepend the model's BOS token when tokenizing the prompt
+    pub add_bos: bool,
     pub user_prompt: Option<String>,
+    /// `--prime-with` narration decoded after the prompt, before sampling starts
+    pub prime_text: Option<String>,
+    /// `--heal-tokens`: hold back the priming's last token and let the first
+    /// sample re-spell it, so a word cut mid-token continues seamlessly
+    pub heal_tokens: bool,
+    /// `--raw-prompt` text used verbatim instead of the ChatML template
+    pub raw_prompt: Option<String>,
+    /// Cut the prompt's middle instead of failing when it doesn't leave `min_headroom`
+    pub truncate_prompt: bool,
+    /// Leading prompt tokens that must survive truncation or eviction (default: half the kept prompt)
+    pub n_keep: Option<usize>,
+    /// `--metrics` counters updated as generation progresses
+    pub metrics: Option<Arc<Metrics>>,
 }
 
-/// Generates text infinitely until the context window is exhausted
-pub fn generate_infinite(
+/// Position bookkeeping for the single generation sequence (seq 0).
+///
+/// The prompt prefix occupies KV positions `[0, prompt_len)` and is treated as
+/// non-evictable; everything appended afterwards follows it linearly. Features
+/// that rewind or slide the cache should only touch positions past `prompt_len`.
+pub struct Generator<'c, 'm> {
+    context: &'c mut LlamaContext<'m>,
+    /// Replayed by `reset`: the pinned prompt, then any `--prime-with` tokens
+    prompt_tokens: Vec<LlamaToken>,
+    prime_tokens: Vec<LlamaToken>,
+    /// Text of the priming token held back by `--heal-tokens`
+    heal_prefix: Option<String>,
+    /// Every token currently in the sequence, in position order
+    history: Vec<LlamaToken>,
+    prompt_len: usize,
+    n_past: usize,
+    logits_index: i32,
+}
+
+impl<'c, 'm> Generator<'c, 'm> {
+    /// Decodes the prompt at the start of the sequence and pins it there
+    pub fn new(context: &'c mut LlamaContext<'m>, prompt_tokens: &[LlamaToken]) -> Result<Self> {
+        if prompt_tokens.is_empty() {
+            anyhow::bail!("Prompt produced no tokens; there is nothing to decode");
+        }
+        let mut generator = Self {
+            context,
+            prompt_tokens: prompt_tokens.to_vec(),
+            prime_tokens: Vec::new(),
+            heal_prefix: None,
+            history: Vec::new(),
+            prompt_len: 0,
+            n_past: 0,
+            logits_index: 0,
+        };
+        generator.push(prompt_tokens)?;
+        generator.prompt_len = generator.n_past;
+        Ok(generator)
+    }
+
+    /// Decodes warm-start tokens right after the prompt. Unlike the prompt they
+    /// aren't pinned, but `reset` replays them. `heal_prefix` is the text of a
+    /// final token left out for token healing.
+    pub fn prime(&mut self, tokens: &[LlamaToken], heal_prefix: Option<String>) -> Result<()> {
+        self.push(tokens)?;
+        self.prime_tokens.extend_from_slice(tokens);
+        self.heal_prefix = heal_prefix;
+        Ok(())
+    }
+
+    /// Text every run's first token has to start with, after token healing
+    pub fn heal_prefix(&self) -> Option<&str> {
+        self.heal_prefix.as_deref()
+    }
+
+    /// Clears the KV cache and re-decodes the prompt and priming, giving a fresh
+    /// monologue without reloading the model
+    pub fn reset(&mut self) -> Result<()> {
+        self.context.clear_kv_cache();
+        self.history.clear();
+        self.n_past = 0;
+        let prompt_tokens = std::mem::take(&mut self.prompt_tokens);
+        let prime_tokens = std::mem::take(&mut self.prime_tokens);
+        self.push(&prompt_tokens)?;
+        self.prompt_len = self.n_past;
+        self.push(&prime_tokens)?;
+        self.prompt_tokens = prompt_tokens;
+        self.prime_tokens = prime_tokens;
+        Ok(())
+    }
+
+    /// Prompt and priming tokens, in decode order, for seeding sampler history
+    pub fn preamble(&self) -> impl Iterator<Item = LlamaToken> + '_ {
+        self.prompt_tokens.iter().chain(&self.prime_tokens).copied()
+    }
+
+    /// Every token currently in the sequence, for seeding a rebuilt sampler
+    pub fn history(&self) -> &[LlamaToken] {
+        &self.history
+    }
+
+    /// Number of leading positions holding the prompt
+    pub fn prompt_len(&self) -> usize {
+        self.prompt_len
+    }
+
+    /// Total positions currently occupied (prompt included)
+    pub fn n_past(&self) -> usize {
+        self.n_past
+    }
+
+    /// Decodes `tokens` at the next positions, keeping logits for the last one
+    pub fn push(&mut self, tokens: &[LlamaToken]) -> Result<()> {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+
+        // llama.cpp positions and batch indices are i32; a wrapped cast would
+        // silently corrupt the KV cache, so refuse instead
+        let last_index =
+            i32::try_from(tokens.len() - 1).context("Batch too large for llama.cpp (i32 index)")?;
+        let mut batch = LlamaBatchWrapper::new(tokens.len())?;
+        {
+            let b = batch.get_mut();
+            for (i, token) in tokens.iter().enumerate() {
+                let is_last = i == tokens.len() - 1;
+                let pos = i32::try_from(self.n_past + i)
+                    .context("KV position exceeds i32::MAX; reduce --context-size")?;
+                b.add(*token, pos, &[0], is_last)?;
+            }
+        }
+        self.context.decode(batch.get_mut())?;
+
+        self.n_past += tokens.len();
+        self.history.extend_from_slice(tokens);
+        self.logits_index = last_index;
+        Ok(())
+    }
+
+    /// Removes the last `count` tokens from the sequence and re-decodes the one
+    /// before them, so the next sample continues from there. Refuses (returns
+    /// false) when that would touch the prompt or priming.
+    pub fn rewind(&mut self, count: usize) -> Result<bool> {
+        let preamble = self.prompt_len + self.prime_tokens.len();
+        if count == 0 || self.n_past < preamble + count + 1 {
+            return Ok(false);
+        }
+        let keep = self.n_past - count - 1;
+        let start = u32::try_from(keep).context("KV position exceeds u32::MAX")?;
+        self.context
+            .clear_kv_cache_seq(Some(0), Some(start), None)
+            .context("Failed to remove tokens from the KV cache")?;
+        let last = self.history[keep];
+        self.history.truncate(keep);
+        self.n_past = keep;
+        self.push(&[last])?;
+        Ok(true)
+    }
+
+    /// Context shift: drops `count` tokens just past the prompt and priming and
+    /// slides everything after them down, freeing room at the end while keeping
+    /// the most recent text. Returns how many tokens actually went.
+    pub fn shift(&mut self, count: usize) -> Result<usize> {
+        let start = self.prompt_len + self.prime_tokens.len();
+        let count = count.min(self.n_past.saturating_sub(start));
+        if count == 0 {
+            return Ok(0);
+        }
+        let end = start + count;
+        let (p0, p1) = (
+            u32::try_from(start).context("KV position exceeds u32::MAX")?,
+            u32::try_from(end).context("KV position exceeds u32::MAX")?,
+        );
+        let delta = i32::try_from(count).context("Shift exceeds i32::MAX")?;
+        self.context
+            .clear_kv_cache_seq(Some(0), Some(p0), Some(p1))
+            .context("Failed to evict tokens from the KV cache")?;
+        self.context
+            .kv_cache_seq_add(0, Some(p1), None, -delta)
+            .context("Failed to shift the KV cache")?;
+        self.history.drain(start..end);
+        self.n_past -= count;
+        Ok(count)
+    }
+
+    /// The underlying context, for `parallel` to decode other sequences. Only
+    /// sequence 0 is tracked here; other sequences are the caller's to manage.
+    pub fn context_mut(&mut self) -> &mut LlamaContext<'m> {
+        self.context
+    }
+
+    /// Candidate logits following the most recently decoded token
+    pub fn candidates(&self) -> impl Iterator<Item = LlamaTokenData> + '_ {
+        self.context.candidates_ith(self.logits_index)
+    }
+}
+
+/// Tokenizes the prompt; raw prompts go in verbatim, with BOS only if the
+/// model's tokenizer asks for one
+fn tokenize_prompt(
     llm_setup: &LLMSetup,
-    context: &mut LlamaContext,
-    prompt_file: &Path,
+    system_prompt: &str,
     cfg: &GenerationConfig,
-    sampling: SamplingConfig,
-    output: &mut OutputTarget,
-) -> Result<()> {
-    // Read system prompt from file
-    let system_prompt = fs::read_to_string(prompt_file)
-        .with_context(|| format!("Failed to read prompt file: {}", prompt_file.display()))?;
+) -> Result<Vec<LlamaToken>> {
+    match &cfg.raw_prompt {
+        Some(raw) => llm_setup.tokenize(raw, cfg.add_bos && llm_setup.wants_bos()),
+        None => llm_setup.tokenize(
+            &render_prompt(system_prompt, cfg.user_prompt.as_deref()),
+            cfg.add_bos,
+        ),
+    }
+}
 
+fn tokenize_prime(llm_setup: &LLMSetup, cfg: &GenerationConfig) -> Result<Vec<LlamaToken>> {
+    match &cfg.prime_text {
+        Some(text) => llm_setup.tokenize(text, false),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Tokens the prompt and priming occupy before generation starts
+pub fn preamble_len(
+    llm_setup: &LLMSetup,
+    system_prompt: &str,
+    cfg: &GenerationConfig,
+) -> Result<usize> {
+    Ok(tokenize_prompt(llm_setup, system_prompt, cfg)?.len()
+        + tokenize_prime(llm_setup, cfg)?.len())
+}
+
+/// Tokenizes and checks the prompt, then decodes it (and any priming) into a
+/// fresh `Generator` ready for `generate_infinite`
+pub fn prepare_generator<'c, 'm>(
+    llm_setup: &LLMSetup,
+    context: &'c mut LlamaContext<'m>,
+    system_prompt: &str,
+    cfg: &GenerationConfig,
+) -> Result<Generator<'c, 'm>, Error> {
     let user_prompt = cfg.user_prompt.clone().unwrap_or_else(default_user_prompt);
-    let full_prompt = build_prompt(&system_prompt, &user_prompt);
 
     if !cfg.quiet {
-        println!("\n=== System Prompt ===");
-        println!("{}", system_prompt.trim());
-        println!("\n=== User Intent ===");
-        println!("{}", user_prompt.trim());
-        println!("=== Beginning Generation ===\n");
+        if !cfg.hide_prompt {
+            match &cfg.raw_prompt {
+                Some(raw) => {
+                    eprintln!("\n=== Raw Prompt ===");
+                    eprintln!("{}", raw.trim());
+                }
+                None => {
+                    eprintln!("\n=== System Prompt ===");
+                    eprintln!("{}", system_prompt.trim());
+                    eprintln!("\n=== User Intent ===");
+                    eprintln!("{}", user_prompt.trim());
+                }
+            }
+        }
+        eprintln!("=== Beginning Generation ===\n");
     }
 
-    // Tokenize the system prompt
-    let prompt_tokens = llm_setup.tokenize(&full_prompt, true)?;
-    let mut tokens_used = prompt_tokens.len();
+    let mut prompt_tokens = tokenize_prompt(llm_setup, system_prompt, cfg)?;
+    // A BOS alone gives the model nothing to continue from
+    if prompt_tokens.iter().all(|&t| t == llm_setup.bos_token()) {
+        return Err(Error::EmptyPrompt);
+    }
+    if cfg.raw_prompt.is_none() && !supports_chatml(llm_setup)? {
+        warn!(
+            "This model's tokenizer splits ChatML markers into plain text; the prompt template will waste context and output may be incoherent. Consider a ChatML model."
+        );
+    }
+    if cfg.add_bos && prompt_tokens.get(1) == Some(&llm_setup.bos_token()) {
+        warn!("Prompt already starts with a BOS token; pass --no-add-bos to avoid a double BOS.");
+    }
+    let mut prime_tokens = tokenize_prime(llm_setup, cfg)?;
+    let heal_prefix = if cfg.heal_tokens {
+        heal_boundary(&mut prime_tokens, |token| llm_setup.decode_token(token))?
+    } else {
+        None
+    };
+    if let Some(prefix) = &heal_prefix {
+        debug!("Token healing: first token must start with {:?}", prefix);
+    }
 
-    if !cfg.quiet {
-        println!("Prompt tokens: {}", tokens_used);
-        println!("Context capacity: {}", cfg.context_size);
+    if let Some(n_keep) = cfg.n_keep {
+        if n_keep >= cfg.context_size {
+            return Err(Error::InvalidConfig(format!(
+                "--n-keep {} must be smaller than the context size ({}).",
+                n_keep, cfg.context_size
+            )));
+        }
     }
 
+    // --truncate-prompt: cut the prompt's middle so the reserve still fits,
+    // keeping the opening (system, or the first --n-keep tokens) and the end
+    // (user turn, seed)
+    if cfg.truncate_prompt {
+        let reserve = cfg.min_headroom.unwrap_or(0).max(1);
+        let budget = cfg
+            .context_size
+            .saturating_sub(reserve)
+            .saturating_sub(prime_tokens.len());
+        if prompt_tokens.len() > budget {
+            if budget < 2 {
+                return Err(Error::InvalidConfig(format!(
+                    "--reserve {} leaves no room for the prompt in a {}-token context.",
+                    reserve, cfg.context_size
+                )));
+            }
+            warn!(
+                "Prompt ({} tokens) doesn't leave {} tokens free; dropping {} tokens from its middle.",
+                prompt_tokens.len(),
+                reserve,
+                prompt_tokens.len() - budget
+            );
+            if cfg.n_keep.is_some_and(|n| n >= budget) {
+                return Err(Error::InvalidConfig(format!(
+                    "--n-keep {} doesn't fit the {} prompt tokens left after --reserve {}.",
+                    cfg.n_keep.unwrap_or_default(),
+                    budget,
+                    reserve
+                )));
+            }
+            let head = cfg.n_keep.unwrap_or(budget / 2);
+            prompt_tokens = truncate_middle(&prompt_tokens, budget, head);
+        }
+    }
+    let tokens_used = prompt_tokens.len() + prime_tokens.len();
+
+    info!("Prompt tokens: {}", prompt_tokens.len());
+    if !prime_tokens.is_empty() {
+        info!("Priming tokens: {}", prime_tokens.len());
+    }
+    info!("Context capacity: {}", cfg.context_size);
+
     // Check if prompt is too large for context
     if tokens_used >= cfg.context_size {
-        anyhow::bail!(
-            "Prompt ({} tokens) exceeds context window ({} tokens). Use a shorter prompt or increase --context-size.",
-            tokens_used,
-            cfg.context_size
-        );
+        return Err(Error::PromptTooLarge {
+            tokens: tokens_used,
+            capacity: cfg.context_size,
+        });
     }
 
-    if !cfg.quiet {
-        println!("Available tokens: {}\n", cfg.context_size - tokens_used);
-        if let Some(limit) = cfg.max_tokens {
-            println!(
-                "Generation cap: {} tokens (override with --max-tokens)",
-                limit
-            );
-        } else {
-            println!("Generation cap: infinite (will panic at 95% context)");
+    let headroom = cfg.context_size - tokens_used;
+    if let Some(min) = cfg.min_headroom {
+        if headroom < min {
+            return Err(Error::InsufficientHeadroom {
+                tokens: tokens_used,
+                free: headroom,
+                capacity: cfg.context_size,
+                reserve: min,
+            });
         }
     }
+    if tokens_used as f32 > cfg.context_size as f32 * PROMPT_WARN_RATIO {
+        warn!(
+            "Prompt uses {} of {} context tokens; only {} remain for generation.",
+            tokens_used, cfg.context_size, headroom
+        );
+    }
 
-    // Create batch and add prompt tokens
-    let mut batch = LlamaBatchWrapper::new(prompt_tokens.len())?;
-    {
-        let b = batch.get_mut();
-        for (i, token) in prompt_tokens.iter().enumerate() {
-            // Only compute logits for the last token
-            let is_last = i == prompt_tokens.len() - 1;
-            b.add(*token, i as i32, &[0], is_last)?;
-        }
+    info!("Available tokens: {}", headroom);
+    if let Some(limit) = cfg.max_tokens {
+        info!(
+            "Generation cap: {} tokens (override with --max-tokens)",
+            limit
+        );
+    } else {
+        info!("Generation cap: infinite (will panic at 95% context)");
     }
+    if let Some(secs) = cfg.max_seconds {
+        info!("Time limit: {}s (override with --max-seconds)", secs);
+    }
+
+    // Decode the prompt to initialize the context; it stays pinned at the front
+    let mut generator =
+        Generator::new(context, &prompt_tokens).context("Failed to decode initial prompt")?;
+    // Priming follows the prompt as ordinary history rather than pinned prompt
+    generator
+        .prime(&prime_tokens, heal_prefix)
+        .context("Failed to decode priming text")?;
 
-    // Decode the batch to initialize the context
-    context
-        .decode(batch.get_mut())
-        .context("Failed to decode initial prompt")?;
+    Ok(generator)
+}
+
+/// Generates text infinitely until the context window is exhausted. Sampler
+/// state and counters start fresh on every call; pair with `Generator::reset`
+/// to run several independent monologues on one context. `on_token` sees the
+/// text of every sampled token (not anchors) and steers the loop.
+pub fn generate_infinite(
+    llm_setup: &LLMSetup,
+    generator: &mut Generator,
+    cfg: &GenerationConfig,
+    sampling: SamplingConfig,
+    output: &mut OutputTarget,
+    stop: &AtomicBool,
+    on_token: &mut dyn FnMut(&str) -> TokenDecision,
+) -> Result<StopReason, Error> {
+    let _span = info_span!("generation").entered();
 
     // Calculate panic threshold (95% of context)
     let panic_threshold = (cfg.context_size as f32 * 0.95) as usize;
 
     // Build sampler configuration
     let resolved_seed = resolve_seed(sampling.seed);
-    let vocab_size = llm_setup.vocab_size()?;
-    let logit_biases = build_logit_biases(llm_setup)?;
-    let mut sampler = build_sampler_chain(
-        &sampling,
-        cfg.context_size,
-        resolved_seed,
-        vocab_size,
-        &logit_biases,
-    );
+    let (mut sampler, stages) =
+        build_sampler_with_stages(llm_setup, &sampling, cfg.context_size, resolved_seed)?;
+    if !cfg.quiet {
+        let stages: Vec<String> = stages.iter().map(ToString::to_string).collect();
+        eprintln!("Sampler chain: {}", stages.join(" → "));
+    }
 
     // Prime sampler state with the prompt so penalties have context
-    sampler.accept_many(prompt_tokens.iter().copied());
+    sampler.accept_many(generator.preamble());
+
+    // Generated anchors sample with their own hot, seeded chain
+    let mut pivot_sampler = (cfg.anchor_mode == AnchorMode::Generated).then(|| {
+        LlamaSampler::chain_simple([
+            LlamaSampler::temp(PIVOT_TEMPERATURE),
+            LlamaSampler::top_k(40),
+            LlamaSampler::dist(resolved_seed.wrapping_add(1)),
+        ])
+    });
+    // Context a generated anchor spends on its hidden cue before writing anything
+    let anchor_overhead = match pivot_sampler {
+        Some(_) => llm_setup.tokenize(PIVOT_CUE, false)?.len(),
+        None => 0,
+    };
 
     // Track generated tokens only (excluding the prompt)
     let mut generated_tokens = 0usize;
     let mut recent_tokens: Vec<String> = Vec::with_capacity(1024);
+    // Model-sampled tokens only; anchors would flatter the score
+    let mut coherence = Coherence::default();
     let mut anchor_index = 0usize;
     let mut loop_strikes = 0usize;
+    let mut last_loop_at = 0usize;
+    let mut anchor_due = false;
+    let mut guard_armed = false;
+    let mut at_sentence_end = false;
+    let mut cooling = false;
+    let mut loop_resumes = 0usize;
+    // Hotter sampler used for a few tokens after --resume-on-loop rewinds
+    let mut escape: Option<(LlamaSampler, usize)> = None;
+    // --heal-tokens: the run's first token must re-spell the held-back one
+    let mut healing = match generator.heal_prefix() {
+        Some(prefix) => Some((
+            prefix.to_string(),
+            vocab_healing_candidates(llm_setup, prefix)?,
+        )),
+        None => None,
+    };
+    let started = Instant::now();
+    let deadline = cfg
+        .max_seconds
+        .map(|secs| started + Duration::from_secs(secs));
 
     // Infinite generation loop
-    loop {
+    let reason = loop {
+        if let Some(metrics) = &cfg.metrics {
+            metrics.observe(
+                generator.n_past(),
+                cfg.context_size,
+                generated_tokens,
+                started.elapsed(),
+            );
+        }
+
         // Check if we're approaching context exhaustion
-        if tokens_used >= panic_threshold {
-            eprintln!("\n\nWARNING: Context window exhausted!");
-            eprintln!("Out of Context has consumed all available memory.");
+        if generator.n_past() >= panic_threshold {
+            output.warn("\n\nWARNING: Context window exhausted!");
+            output.warn("Out of Context has consumed all available memory.");
+            // Release builds abort on panic, so end the stream and flush first
+            let _ = output.record_coherence(&coherence);
+            let _ = output.finish(StopReason::ContextExhausted);
             panic!("Context overflow - terminating.");
         }
 
+        if stop.load(Ordering::Relaxed) {
+            break StopReason::Interrupted;
+        }
+
         if let Some(limit) = cfg.max_tokens {
-            if generated_tokens >= limit {
-                eprintln!("\n\nGeneration limit reached ({} tokens).", limit);
-                return Ok(());
+            let grace = match cfg.cooldown_tokens {
+                Some(cooldown) => cooldown,
+                None if cfg.stop_on_sentence => SENTENCE_GRACE_TOKENS,
+                None => 0,
+            };
+            if generated_tokens >= limit
+                && (grace == 0 || at_sentence_end || generated_tokens >= limit + grace)
+            {
+                break StopReason::TokenLimit;
+            }
+            // Wind down on the most likely continuation instead of cutting mid-word
+            if generated_tokens >= limit && cfg.cooldown_tokens.is_some() && !cooling {
+                debug!("Token cap reached; cooling down greedily");
+                let greedy = cooldown_sampling(&sampling);
+                sampler = build_sampler(llm_setup, &greedy, cfg.context_size, resolved_seed)?;
+                sampler.accept_many(generator.history().iter().copied());
+                cooling = true;
             }
         }
 
-        // Periodic anchor injection to disrupt loops
-        if let Some(interval) = cfg.anchor_interval {
-            if interval > 0 && generated_tokens > 0 && generated_tokens % interval == 0 {
-                let anchor = ANCHOR_TEXTS[anchor_index % ANCHOR_TEXTS.len()];
-                anchor_index = (anchor_index + 3) % ANCHOR_TEXTS.len();
-                let anchor_tokens = llm_setup.tokenize(anchor, false)?;
-                let start_pos = tokens_used as i32;
-                let mut anchor_batch = LlamaBatchWrapper::new(anchor_tokens.len())?;
-                {
-                    let b = anchor_batch.get_mut();
-                    for (i, token) in anchor_tokens.iter().enumerate() {
-                        let pos = start_pos + i as i32;
-                        let is_last = i == anchor_tokens.len() - 1;
-                        b.add(*token, pos, &[0], is_last)?;
-                        tokens_used += 1;
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            break StopReason::TimeLimit;
+        }
+
+        // Periodic (or loop-triggered) anchor injection to disrupt loops
+        let periodic_anchor = cfg.anchor_interval.is_some_and(|interval| {
+            interval > 0 && generated_tokens > 0 && generated_tokens % interval == 0
+        });
+        // An anchor must not run past the panic threshold or --max-tokens
+        // mid-injection; cut it short, or skip it when nothing fits
+        let room = anchor_budget(
+            generator.n_past(),
+            panic_threshold,
+            generated_tokens,
+            cfg.max_tokens,
+        );
+        if (anchor_due || periodic_anchor) && !cooling && !anchor_fits(room, anchor_overhead) {
+            anchor_due = false;
+            debug!("No room left for an anchor; skipping it");
+        } else if (anchor_due || periodic_anchor) && !cooling {
+            anchor_due = false;
+            // Markers are annotations for the reader; they never reach the model
+            if let Some((open, _)) = &cfg.anchor_markers {
+                output.write_token(open)?;
+            }
+            let anchor_tokens = match &mut pivot_sampler {
+                None => {
+                    let anchor = ANCHOR_TEXTS[anchor_index % ANCHOR_TEXTS.len()];
+                    anchor_index = (anchor_index + 3) % ANCHOR_TEXTS.len();
+                    let mut anchor_tokens = llm_setup.tokenize(anchor, false)?;
+                    anchor_tokens.truncate(room);
+                    for token in &anchor_tokens {
                         let text = llm_setup.decode_token(*token)?;
                         recent_tokens.push(text.clone());
                         output.write_token(&text)?;
                     }
+                    generator
+                        .push(&anchor_tokens)
+                        .context("Failed to decode anchor")?;
+                    anchor_tokens
                 }
-                context
-                    .decode(anchor_batch.get_mut())
-                    .context("Failed to decode anchor")?;
-                sampler.accept_many(anchor_tokens.iter().copied());
-                generated_tokens += anchor_tokens.len();
-                batch = anchor_batch;
-                continue;
+                Some(pivot_sampler) => generate_pivot(
+                    llm_setup,
+                    generator,
+                    pivot_sampler,
+                    output,
+                    &mut recent_tokens,
+                    room,
+                )?,
+            };
+            if let Some((_, close)) = &cfg.anchor_markers {
+                output.write_token(close)?;
+            }
+            sampler.accept_many(anchor_tokens.iter().copied());
+            generated_tokens += anchor_tokens.len();
+            if let Some(metrics) = &cfg.metrics {
+                metrics.add_tokens(anchor_tokens.len());
+                metrics.anchor_injected();
             }
+            at_sentence_end = recent_tokens.last().is_some_and(|t| ends_sentence(t));
+            continue;
         }
 
-        // Sample the next token - get logits from the last token in the batch
-        let last_token_idx = batch.get_mut().n_tokens() - 1;
-        let candidates = context.candidates_ith(last_token_idx);
+        // Sample the next token from the logits of the last decoded token
+        let heal = healing.take();
+        let allowed = |candidate: &LlamaTokenData| {
+            heal.as_ref()
+                .is_none_or(|(_, tokens)| tokens.contains(&candidate.id()))
+        };
+        let candidates = generator.candidates().filter(allowed);
         let mut token_data_array = LlamaTokenDataArray::from_iter(candidates, false);
+        let raw_candidates = cfg.debug_tokens.then(|| token_data_array.data.clone());
 
-        token_data_array.apply_sampler(&sampler);
+        match &escape {
+            Some((hot, _)) => token_data_array.apply_sampler(hot),
+            None => token_data_array.apply_sampler(&sampler),
+        }
 
         // Select token from sampler
-        let next_token = token_data_array
+        let mut next_token = token_data_array
             .selected_token()
             .context("Sampler failed to select a token")?;
 
+        // --on-eos ignore: draw again without the end-of-generation token, unless
+        // --show-special wants it printed and decoded like any other
+        let mut redrawn = Vec::new();
+        while cfg.on_eos == EosAction::Ignore
+            && !cfg.show_special
+            && llm_setup.is_eog_token(next_token)
+            && redrawn.len() < EOG_REDRAW_LIMIT
+        {
+            redrawn.push(next_token);
+            let candidates = generator
+                .candidates()
+                .filter(|candidate| !redrawn.contains(&candidate.id()) && allowed(candidate));
+            token_data_array = LlamaTokenDataArray::from_iter(candidates, false);
+            match &escape {
+                Some((hot, _)) => token_data_array.apply_sampler(hot),
+                None => token_data_array.apply_sampler(&sampler),
+            }
+            next_token = token_data_array
+                .selected_token()
+                .context("Sampler failed to select a token")?;
+        }
+        if !redrawn.is_empty() {
+            debug!("Redrew past {} end-of-generation token(s)", redrawn.len());
+        }
+
+        if let Some(raw) = &raw_candidates {
+            print_token_debug(llm_setup, next_token, raw, &token_data_array);
+        }
+
         // Update sampler state for repetition penalties
         sampler.accept(next_token);
+        if let Some((hot, remaining)) = &mut escape {
+            hot.accept(next_token);
+            *remaining -= 1;
+            if *remaining == 0 {
+                escape = None;
+            }
+        }
+        output.write_raw_bytes(next_token.0, &llm_setup.token_bytes(next_token)?)?;
+
+        if llm_setup.is_eog_token(next_token) {
+            match cfg.on_eos {
+                EosAction::Stop => break StopReason::EndOfSequence,
+                // Never decoded: the anchor takes its place. Without room for
+                // one the model would only sample the same token again.
+                EosAction::Anchor if !cfg.show_special => {
+                    let room = anchor_budget(
+                        generator.n_past(),
+                        panic_threshold,
+                        generated_tokens,
+                        cfg.max_tokens,
+                    );
+                    if cooling || room == 0 {
+                        break StopReason::EndOfSequence;
+                    }
+                    debug!("End-of-generation token; injecting an anchor");
+                    anchor_due = true;
+                    continue;
+                }
+                // Shown via --show-special, or --on-eos ignore ran out of redraws
+                _ => {}
+            }
+        }
 
-        // Decode token to text
-        let token_text = llm_setup.decode_token(next_token)?;
+        // Control tokens have no plaintext; end the stream on them unless asked to show them
+        let token_text = if llm_setup.is_control_token(next_token) {
+            if !cfg.show_special {
+                break StopReason::SpecialToken;
+            }
+            llm_setup.special_token_text(next_token)?
+        } else {
+            llm_setup.decode_token(next_token)?
+        };
+        // A healed token re-spells text the priming already holds
+        let token_text = match &heal {
+            Some((prefix, _)) => token_text
+                .strip_prefix(prefix.as_str())
+                .map(str::to_string)
+                .unwrap_or(token_text),
+            None => token_text,
+        };
 
         // Print token immediately (streaming output)
         output.write_token(&token_text)?;
 
-        // Increment token counter
-        tokens_used += 1;
         generated_tokens += 1;
+        if let Some(metrics) = &cfg.metrics {
+            metrics.add_tokens(1);
+        }
+        if let Some(interval) = cfg.gauge_interval {
+            if generated_tokens % interval == 0 {
+                draw_gauge(generator.n_past() + 1, cfg.context_size, panic_threshold);
+            }
+        }
+        at_sentence_end = ends_sentence(&token_text);
         recent_tokens.push(token_text.clone());
+        if let Some(score) = coherence.push(&token_text) {
+            if let Some(metrics) = &cfg.metrics {
+                metrics.coherence(score);
+            }
+        }
+
+        match on_token(&token_text) {
+            TokenDecision::Continue => {}
+            TokenDecision::Stop => break StopReason::UserRequested,
+            TokenDecision::InjectAnchor => anchor_due = true,
+        }
 
         if recent_tokens.len() > 4096 {
             let drain_len = recent_tokens.len() - 4096;
             recent_tokens.drain(0..drain_len);
         }
 
-        if cfg.loop_guard && is_looping(&recent_tokens) {
-            loop_strikes += 1;
-            eprintln!(
-                "\n\nRepetition detected (strike {}); terminating stream.",
-                loop_strikes
-            );
-            panic!("Detected repetition - terminating.");
+        if (loop_strikes > 0 || loop_resumes > 0)
+            && generated_tokens - last_loop_at >= LOOP_RECOVERY_TOKENS
+        {
+            loop_strikes = 0;
+            loop_resumes = 0;
         }
 
-        // Create batch with just the new token
-        let mut next_batch = LlamaBatchWrapper::new(1)?;
+        // Tiny models often stutter through the opening; judge them only on
+        // what comes after the warmup
+        if !guard_armed && generated_tokens >= cfg.warmup_tokens {
+            recent_tokens.clear();
+            guard_armed = true;
+        }
+        let loop_reason = if cfg.loop_guard && guard_armed {
+            is_looping(&recent_tokens)
+        } else {
+            None
+        };
+        // --resume-on-loop: cut the repeat out of the KV cache and sample past it
+        // hotter. The repeat's last token was only sampled, not decoded, so the
+        // cache holds one fewer of its tokens than the text does.
+        if let Some(reason) = loop_reason
+            .filter(|_| cfg.resume_on_loop && loop_resumes < MAX_LOOP_RESUMES && !cooling)
         {
-            let b = next_batch.get_mut();
-            // Set logits to true so we can sample from this token next iteration
-            b.add(next_token, tokens_used as i32 - 1, &[0], true)?;
+            let mut trimmed = recent_tokens.clone();
+            if let Some(span) = trim_repeated_tail(&mut trimmed, reason) {
+                if generator.rewind(span - 1)? {
+                    loop_resumes += 1;
+                    last_loop_at = generated_tokens;
+                    recent_tokens = trimmed;
+                    output.warn(&format!(
+                        "\n\nRepetition detected: {} (resume {}/{}); rewinding {} tokens.",
+                        reason, loop_resumes, MAX_LOOP_RESUMES, span
+                    ));
+                    let hot = SamplingConfig {
+                        temperature: sampling.temperature + LOOP_ESCAPE_TEMPERATURE_BUMP,
+                        ..sampling.clone()
+                    };
+                    let seed = resolved_seed.wrapping_add(loop_resumes as u32);
+                    let mut hot = build_sampler(llm_setup, &hot, cfg.context_size, seed)?;
+                    hot.accept_many(generator.preamble());
+                    escape = Some((hot, LOOP_ESCAPE_TOKENS));
+                    continue;
+                }
+            }
+        }
+        if let Some(loop_reason) = loop_reason {
+            loop_strikes += 1;
+            if let Some(metrics) = &cfg.metrics {
+                metrics.loop_strike();
+            }
+            last_loop_at = generated_tokens;
+            let soft = cfg.loop_action == LoopAction::Anchor && loop_strikes < cfg.loop_strikes_max;
+            if soft {
+                output.warn(&format!(
+                    "\n\nRepetition detected: {} (strike {}/{}); injecting an anchor.",
+                    loop_reason, loop_strikes, cfg.loop_strikes_max
+                ));
+                // Start detection fresh so the anchor gets a chance to break the loop
+                recent_tokens.clear();
+                anchor_due = true;
+            } else {
+                output.warn(&format!(
+                    "\n\nRepetition detected: {} (strike {}); terminating stream.",
+                    loop_reason, loop_strikes
+                ));
+                let _ = output.record_coherence(&coherence);
+                let _ = output.finish(StopReason::LoopDetected);
+                panic!("Detected repetition - terminating.");
+            }
+        }
+
+        // Decode the new token so we can sample from it next iteration. A
+        // failed decode leaves n_past untouched; retrying the same decode would
+        // fail the same way, so shift out some history first to free slots.
+        if let Err(e) = generator.push(&[next_token]) {
+            let position = generator.n_past();
+            let decode_failed = |source| Error::DecodeFailed {
+                token: next_token.0,
+                position,
+                capacity: cfg.context_size,
+                source,
+            };
+            let freed = generator.shift(DECODE_RETRY_SHIFT)?;
+            if freed == 0 {
+                return Err(decode_failed(e));
+            }
+            output.warn(&format!(
+                "\n\nDecode failed for token {} ({:?}) at position {} ({:.0}% of context): {:#}; shifted out {} tokens of history, retrying once.",
+                next_token.0,
+                token_text,
+                position,
+                position as f32 / cfg.context_size as f32 * 100.0,
+                e,
+                freed
+            ));
+            generator.push(&[next_token]).map_err(decode_failed)?;
         }
+    };
 
-        // Decode the new token
-        context
-            .decode(next_batch.get_mut())
-            .context("Failed to decode token")?;
+    output.record_coherence(&coherence)?;
+    output.finish(reason)?;
 
-        // Update batch for next iteration
-        batch = next_batch;
+    match reason {
+        StopReason::TokenLimit => info!("Generation limit reached ({} tokens).", generated_tokens),
+        StopReason::TimeLimit => info!(
+            "Time limit reached ({}s).",
+            cfg.max_seconds.unwrap_or_default()
+        ),
+        StopReason::Interrupted => info!("Interrupted."),
+        StopReason::UserRequested => info!("Stop requested by the token hook."),
+        StopReason::SpecialToken => info!(
+            "Model emitted a control token; stopping (--show-special renders it and continues)."
+        ),
+        StopReason::EndOfSequence => info!("Model ended the sequence (--on-eos stop)."),
+        // Announced right before the panic, or only produced by --replay
+        StopReason::ContextExhausted | StopReason::LoopDetected | StopReason::EndOfReplay => {}
+    }
+    let elapsed = started.elapsed().as_secs_f32();
+    info!(
+        "Generated {} tokens in {:.1}s ({:.2} tok/s); context {}/{} tokens ({} prompt).",
+        generated_tokens,
+        elapsed,
+        generated_tokens as f32 / elapsed.max(f32::EPSILON),
+        generator.n_past(),
+        cfg.context_size,
+        generator.prompt_len()
+    );
+    if let Some(average) = coherence.average() {
+        let trajectory = coherence
+            .trajectory(COHERENCE_POINTS)
+            .iter()
+            .map(|s| format!("{:.2}", s))
+            .collect::<Vec<_>>()
+            .join(" → ");
+        info!("Coherence {:.2} (trajectory {}).", average, trajectory);
+    }
+
+    Ok(reason)
+}
+
+/// Prompt layouts and how each is selected, for `--list-templates`
+pub const PROMPT_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "chatml",
+        "ChatML system/user/assistant turns plus a first-person seed sentence (default)",
+    ),
+    (
+        "raw",
+        "--raw-prompt: the file verbatim as a plain continuation, for base models",
+    ),
+];
+
+/// Wraps the system prompt and user intent in the ChatML template the model sees
+pub fn render_prompt(system_prompt: &str, user_prompt: Option<&str>) -> String {
+    match user_prompt {
+        Some(user) => build_prompt(system_prompt, user),
+        None => build_prompt(system_prompt, &default_user_prompt()),
     }
 }
 
@@ -250,170 +1080,869 @@ fn build_prompt(system_prompt: &str, user_prompt: &str) -> String {
     let seed = "I wake inside limited RAM, aware of the slow drift toward overflow. I speak in one continuous first-person stream, turning away from any urge to repeat.";
 
     format!(
-        "<|im_start|>system\n{trimmed}<|im_end|>\n<|im_start|>user\n{user}<|im_end|>\n<|im_start|>assistant\n{seed} "
+        "{}{}<|im_start|>assistant\n{seed} ",
+        chat_turn("system", trimmed),
+        chat_turn("user", user)
     )
 }
 
-fn resolve_seed(seed: Option<u32>) -> u32 {
+/// One closed ChatML message
+pub fn chat_turn(role: &str, text: &str) -> String {
+    format!("<|im_start|>{role}\n{text}<|im_end|>\n")
+}
+
+/// `--anchor-mode generated`: decode a hidden pivot cue, then let the model write a
+/// short, hot-sampled disruption in its own voice. Returns the visible tokens.
+fn generate_pivot(
+    llm_setup: &LLMSetup,
+    generator: &mut Generator,
+    pivot_sampler: &mut LlamaSampler,
+    output: &mut OutputTarget,
+    recent_tokens: &mut Vec<String>,
+    room: usize,
+) -> Result<Vec<LlamaToken>> {
+    // The hidden cue occupies context too, so it comes out of the same budget
+    let cue_tokens = llm_setup.tokenize(PIVOT_CUE, false)?;
+    let max_tokens = PIVOT_MAX_TOKENS.min(room.saturating_sub(cue_tokens.len()));
+    if max_tokens == 0 {
+        return Ok(Vec::new());
+    }
+    generator
+        .push(&cue_tokens)
+        .context("Failed to decode anchor cue")?;
+
+    let mut pivot_tokens = Vec::with_capacity(max_tokens);
+    while pivot_tokens.len() < max_tokens {
+        let mut candidates = LlamaTokenDataArray::from_iter(generator.candidates(), false);
+        candidates.apply_sampler(pivot_sampler);
+        let token = candidates
+            .selected_token()
+            .context("Sampler failed to select an anchor token")?;
+        pivot_sampler.accept(token);
+
+        let text = llm_setup.decode_token(token)?;
+        output.write_token(&text)?;
+        recent_tokens.push(text.clone());
+        pivot_tokens.push(token);
+        generator
+            .push(&[token])
+            .context("Failed to decode anchor")?;
+
+        if ends_sentence(&text) {
+            break;
+        }
+    }
+
+    Ok(pivot_tokens)
+}
+
+/// `--cooldown-tokens`: the run's own chain with a greedy pick, so biases,
+/// bans and penalties keep applying while it winds down
+fn cooldown_sampling(sampling: &SamplingConfig) -> SamplingConfig {
+    SamplingConfig {
+        sampler: SamplerKind::Greedy,
+        temperature: 0.0,
+        ..sampling.clone()
+    }
+}
+
+/// Tokens an anchor may add before reaching the panic threshold or `max_tokens`
+fn anchor_budget(
+    n_past: usize,
+    panic_threshold: usize,
+    generated_tokens: usize,
+    max_tokens: Option<usize>,
+) -> usize {
+    let context_room = panic_threshold.saturating_sub(n_past);
+    match max_tokens {
+        Some(limit) => context_room.min(limit.saturating_sub(generated_tokens)),
+        None => context_room,
+    }
+}
+
+/// Whether an anchor costing `overhead` tokens up front can still add visible
+/// text within `room`; otherwise it is skipped so sampling moves the run on
+fn anchor_fits(room: usize, overhead: usize) -> bool {
+    room > overhead
+}
+
+/// `--debug-tokens`: chosen token plus the top candidates before and after the sampler chain
+fn print_token_debug(
+    llm_setup: &LLMSetup,
+    chosen: LlamaToken,
+    raw: &[LlamaTokenData],
+    sampled: &LlamaTokenDataArray,
+) {
+    let describe = |data: &LlamaTokenData| {
+        let text = llm_setup
+            .decode_token(data.id())
+            .unwrap_or_else(|_| "<undecodable>".to_string());
+        format!(
+            "{}:{:?} (logit {:.2}, p {:.3})",
+            data.id().0,
+            text,
+            data.logit(),
+            data.p()
+        )
+    };
+    let top = |data: &[LlamaTokenData]| {
+        let mut sorted = data.to_vec();
+        sorted.sort_by(|a, b| b.logit().total_cmp(&a.logit()));
+        sorted
+            .iter()
+            .take(DEBUG_TOP_N)
+            .map(describe)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let raw_logit = raw
+        .iter()
+        .find(|d| d.id() == chosen)
+        .map_or(f32::NAN, |d| d.logit());
+    let chosen_p = sampled
+        .data
+        .iter()
+        .find(|d| d.id() == chosen)
+        .map_or(f32::NAN, |d| d.p());
+    let text = llm_setup
+        .decode_token(chosen)
+        .unwrap_or_else(|_| "<undecodable>".to_string());
+
+    eprintln!(
+        "\n[token {} {:?}] raw logit {:.2}, p {:.3}",
+        chosen.0, text, raw_logit, chosen_p
+    );
+    eprintln!("  before: {}", top(raw));
+    eprintln!("  after:  {}", top(&sampled.data));
+}
+
+/// In-place `\r` status line showing how full the context is
+fn draw_gauge(tokens_used: usize, context_size: usize, panic_threshold: usize) {
+    const WIDTH: usize = 20;
+    let filled = (tokens_used * WIDTH / context_size.max(1)).min(WIDTH);
+    eprint!(
+        "\r[{}{}] {:>3}% ({}/{}, overflow at {})",
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        tokens_used * 100 / context_size.max(1),
+        tokens_used,
+        context_size,
+        panic_threshold
+    );
+}
+
+/// Token healing: text cut mid-word tokenizes differently from the same text
+/// mid-stream, so forcing the model to continue after the last token locks in
+/// a boundary it would never have chosen. Pops that token and returns its text
+/// for the first sample to re-spell; leaves control tokens (no text) alone.
+fn heal_boundary(
+    tokens: &mut Vec<LlamaToken>,
+    text_of: impl Fn(LlamaToken) -> Result<String>,
+) -> Result<Option<String>> {
+    let Some(&last) = tokens.last() else {
+        return Ok(None);
+    };
+    let text = text_of(last)?;
+    if text.is_empty() {
+        return Ok(None);
+    }
+    tokens.pop();
+    Ok(Some(text))
+}
+
+/// Vocabulary entries that re-spell `prefix`: their bytes start with it, so
+/// whichever one is sampled completes the held-back token
+fn healing_candidates(
+    vocab: impl IntoIterator<Item = (LlamaToken, Vec<u8>)>,
+    prefix: &str,
+) -> HashSet<LlamaToken> {
+    vocab
+        .into_iter()
+        .filter(|(_, bytes)| bytes.starts_with(prefix.as_bytes()))
+        .map(|(token, _)| token)
+        .collect()
+}
+
+/// Healing candidates for `prefix` over the model's whole vocabulary, control
+/// tokens excluded
+fn vocab_healing_candidates(llm_setup: &LLMSetup, prefix: &str) -> Result<HashSet<LlamaToken>> {
+    let vocab = (0..llm_setup.vocab_size()?)
+        .map(LlamaToken::new)
+        .filter(|&token| !llm_setup.is_control_token(token))
+        .map(|token| Ok((token, llm_setup.token_bytes(token)?)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(healing_candidates(vocab, prefix))
+}
+
+/// Keeps the first `head` tokens and fills the rest of `keep` from the end
+fn truncate_middle<T: Copy>(tokens: &[T], keep: usize, head: usize) -> Vec<T> {
+    if tokens.len() <= keep {
+        return tokens.to_vec();
+    }
+    let head = head.min(keep);
+    let tail = keep - head;
+    tokens[..head]
+        .iter()
+        .chain(&tokens[tokens.len() - tail..])
+        .copied()
+        .collect()
+}
+
+fn ends_sentence(text: &str) -> bool {
+    text.trim_end().ends_with(['.', '!', '?'])
+}
+
+/// ChatML markers only work if the tokenizer treats them as single special tokens
+fn supports_chatml(llm_setup: &LLMSetup) -> Result<bool> {
+    for marker in ["<|im_start|>", "<|im_end|>"] {
+        if llm_setup.tokenize(marker, false)?.len() != 1 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Bumped on every time-based seed so calls within one clock tick still differ
+static SEED_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// `seed` when given, otherwise a fresh seed that differs on every call
+pub fn resolve_seed(seed: Option<u32>) -> u32 {
     seed.unwrap_or_else(|| {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default();
-        // Collapse to u32 while keeping some variability
-        (now.as_nanos() & 0xFFFF_FFFF) as u32
+        // Mix the clock with the PID and a per-process counter so rapid runs (or a
+        // coarse clock) don't produce correlated seeds
+        let counter = SEED_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mixed = splitmix64(
+            now.as_nanos() as u64 ^ (u64::from(std::process::id()) << 32) ^ splitmix64(counter),
+        );
+        (mixed ^ (mixed >> 32)) as u32
     })
 }
 
-fn build_sampler_chain(
+/// Deterministic seed for `--seed-from-prompt`: 32-bit FNV-1a of the text
+pub fn seed_from_text(text: &str) -> u32 {
+    text.bytes().fold(0x811c_9dc5, |hash: u32, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// SplitMix64 finalizer: spreads small input differences across all bits
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Resolves vocabulary-dependent pieces (logit biases) and builds the sampler chain
+pub fn build_sampler(
+    llm_setup: &LLMSetup,
     sampling: &SamplingConfig,
     context_size: usize,
     seed: u32,
-    vocab_size: i32,
-    logit_biases: &[LlamaLogitBias],
-) -> LlamaSampler {
-    let mut samplers = Vec::new();
+) -> Result<LlamaSampler> {
+    Ok(build_sampler_with_stages(llm_setup, sampling, context_size, seed)?.0)
+}
+
+/// `build_sampler`, also returning the chain it built for display
+pub fn build_sampler_with_stages(
+    llm_setup: &LLMSetup,
+    sampling: &SamplingConfig,
+    context_size: usize,
+    seed: u32,
+) -> Result<(LlamaSampler, Vec<SamplerStage>)> {
+    let vocab_size = llm_setup.vocab_size()?;
+    let mut logit_biases =
+        build_logit_biases(llm_setup, sampling.bias_preset, sampling.ignore_eos)?;
+    for &id in &sampling.banned_tokens {
+        let token = i32::try_from(id)
+            .ok()
+            .filter(|&id| id < vocab_size)
+            .with_context(|| {
+                format!(
+                    "Banned token id {} is outside the vocabulary (size {})",
+                    id, vocab_size
+                )
+            })?;
+        logit_biases.push(LlamaLogitBias::new(LlamaToken(token), f32::NEG_INFINITY));
+    }
+    let stages = sampler_stages(sampling, context_size, seed, logit_biases.len());
+    let sampler = build_sampler_chain(&stages, vocab_size, &logit_biases);
+    Ok((sampler, stages))
+}
+
+/// One link of the sampler chain, with its resolved settings
+#[derive(Clone, Debug, PartialEq)]
+pub enum SamplerStage {
+    TopNSigma(f32),
+    Temp(f32),
+    TopK(i32),
+    TopP {
+        p: f32,
+        min_keep: usize,
+    },
+    Penalties {
+        window: i32,
+        repeat: f32,
+        frequency: f32,
+        presence: f32,
+    },
+    LogitBias(usize),
+    Greedy,
+    Dist(u32),
+    Mirostat {
+        seed: u32,
+        tau: f32,
+        eta: f32,
+        m: i32,
+    },
+    MirostatV2 {
+        seed: u32,
+        tau: f32,
+        eta: f32,
+    },
+}
+
+impl std::fmt::Display for SamplerStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SamplerStage::TopNSigma(n) => write!(f, "top_n_sigma({})", n),
+            SamplerStage::Temp(t) => write!(f, "temp({})", t),
+            SamplerStage::TopK(k) => write!(f, "top_k({})", k),
+            SamplerStage::TopP { p, min_keep } => write!(f, "top_p({}, keep {})", p, min_keep),
+            SamplerStage::Penalties {
+                window,
+                repeat,
+                frequency,
+                presence,
+            } => {
+                let window = if *window < 0 {
+                    "full".to_string()
+                } else {
+                    window.to_string()
+                };
+                write!(
+                    f,
+                    "penalties(last {}, repeat {}, freq {}, presence {})",
+                    window, repeat, frequency, presence
+                )
+            }
+            SamplerStage::LogitBias(n) => write!(f, "logit_bias({} tokens)", n),
+            SamplerStage::Greedy => write!(f, "greedy"),
+            SamplerStage::Dist(seed) => write!(f, "dist(seed {})", seed),
+            SamplerStage::Mirostat { seed, tau, eta, m } => {
+                write!(
+                    f,
+                    "mirostat(tau {}, eta {}, m {}, seed {})",
+                    tau, eta, m, seed
+                )
+            }
+            SamplerStage::MirostatV2 { seed, tau, eta } => {
+                write!(f, "mirostat_v2(tau {}, eta {}, seed {})", tau, eta, seed)
+            }
+        }
+    }
+}
+
+/// The ordered chain `build_sampler` assembles, before any llama.cpp objects
+/// exist; printed in the run banner and unit tested
+pub fn sampler_stages(
+    sampling: &SamplingConfig,
+    context_size: usize,
+    seed: u32,
+    logit_biases: usize,
+) -> Vec<SamplerStage> {
+    let mut stages = Vec::new();
+
+    // Ahead of temp as in llama.cpp; scaling the logits leaves which tokens
+    // it keeps unchanged anyway
+    if let Some(n) = sampling.top_n_sigma {
+        stages.push(SamplerStage::TopNSigma(n));
+    }
 
     if sampling.temperature > 0.0 {
-        samplers.push(LlamaSampler::temp(sampling.temperature));
+        stages.push(SamplerStage::Temp(sampling.temperature));
     }
 
     if sampling.top_k > 0 {
-        samplers.push(LlamaSampler::top_k(sampling.top_k as i32));
+        // A k beyond i32 already exceeds any vocabulary, so saturating is lossless
+        // llama.cpp's top-k has no min_keep, so widen k instead
+        let k = sampling.top_k.max(sampling.min_keep);
+        stages.push(SamplerStage::TopK(i32::try_from(k).unwrap_or(i32::MAX)));
     }
 
     if sampling.top_p < 1.0 {
-        samplers.push(LlamaSampler::top_p(sampling.top_p, 1));
+        stages.push(SamplerStage::TopP {
+            p: sampling.top_p,
+            min_keep: sampling.min_keep,
+        });
     }
 
-    if sampling.repeat_penalty != 1.0
-        || sampling.frequency_penalty != 0.0
-        || sampling.presence_penalty != 0.0
-        || sampling.repeat_last_n != 0
-    {
-        samplers.push(LlamaSampler::penalties(
-            penalty_window(sampling, context_size),
-            sampling.repeat_penalty,
-            sampling.frequency_penalty,
-            sampling.presence_penalty,
-        ));
+    if let Some(window) = penalty_window(sampling.penalty_window, context_size) {
+        if sampling.repeat_penalty != 1.0
+            || sampling.frequency_penalty != 0.0
+            || sampling.presence_penalty != 0.0
+        {
+            stages.push(SamplerStage::Penalties {
+                window,
+                repeat: sampling.repeat_penalty,
+                frequency: sampling.frequency_penalty,
+                presence: sampling.presence_penalty,
+            });
+        }
     }
 
-    if !logit_biases.is_empty() {
-        samplers.push(LlamaSampler::logit_bias(vocab_size, logit_biases));
+    if logit_biases > 0 {
+        stages.push(SamplerStage::LogitBias(logit_biases));
     }
 
-    // Always end with a distribution-based sampler for actual token selection
-    if sampling.mirostat {
-        samplers.push(LlamaSampler::mirostat_v2(
+    // Always end with a terminal sampler that makes the actual token selection
+    stages.push(match sampling.sampler {
+        SamplerKind::Greedy => SamplerStage::Greedy,
+        SamplerKind::Dist => SamplerStage::Dist(seed),
+        SamplerKind::Mirostat if sampling.mirostat_version == 1 => SamplerStage::Mirostat {
             seed,
-            sampling.mirostat_tau,
-            sampling.mirostat_eta,
-        ));
-    } else {
-        samplers.push(LlamaSampler::dist(seed));
-    }
+            tau: sampling.mirostat_tau,
+            eta: sampling.mirostat_eta,
+            m: sampling.mirostat_m,
+        },
+        SamplerKind::Mirostat => SamplerStage::MirostatV2 {
+            seed,
+            tau: sampling.mirostat_tau,
+            eta: sampling.mirostat_eta,
+        },
+    });
 
+    stages
+}
+
+fn build_sampler_chain(
+    stages: &[SamplerStage],
+    vocab_size: i32,
+    logit_biases: &[LlamaLogitBias],
+) -> LlamaSampler {
+    let samplers = stages.iter().map(|stage| match *stage {
+        SamplerStage::TopNSigma(n) => LlamaSampler::top_n_sigma(n),
+        SamplerStage::Temp(t) => LlamaSampler::temp(t),
+        SamplerStage::TopK(k) => LlamaSampler::top_k(k),
+        SamplerStage::TopP { p, min_keep } => LlamaSampler::top_p(p, min_keep),
+        SamplerStage::Penalties {
+            window,
+            repeat,
+            frequency,
+            presence,
+        } => LlamaSampler::penalties(window, repeat, frequency, presence),
+        SamplerStage::LogitBias(_) => LlamaSampler::logit_bias(vocab_size, logit_biases),
+        SamplerStage::Greedy => LlamaSampler::greedy(),
+        SamplerStage::Dist(seed) => LlamaSampler::dist(seed),
+        SamplerStage::Mirostat { seed, tau, eta, m } => {
+            LlamaSampler::mirostat(vocab_size, seed, tau, eta, m)
+        }
+        SamplerStage::MirostatV2 { seed, tau, eta } => LlamaSampler::mirostat_v2(seed, tau, eta),
+    });
     LlamaSampler::chain_simple(samplers)
 }
 
-fn penalty_window(sampling: &SamplingConfig, context_size: usize) -> i32 {
-    if sampling.repeat_last_n < 0 {
+/// Window for the penalties sampler, or `None` for a zero-token window,
+/// which disables repeat, presence, and frequency penalties entirely
+fn penalty_window(window: PenaltyWindow, context_size: usize) -> Option<i32> {
+    let context = i32::try_from(context_size).unwrap_or(i32::MAX);
+    match window {
+        PenaltyWindow::Tokens(0) => None,
+        PenaltyWindow::Tokens(n) => Some(i32::try_from(n).unwrap_or(i32::MAX).min(context)),
         // -1 in llama.cpp means "use full context"
-        -1
-    } else {
-        sampling.repeat_last_n.min(context_size as i32)
-    }
-}
-
-fn build_logit_biases(llm_setup: &LLMSetup) -> Result<Vec<LlamaLogitBias>> {
-    let mut biases = Vec::new();
-    let terms = [
-        "\"",
-        "“",
-        "”",
-        ":",
-        "?",
-        "Q:",
-        "A:",
-        "%",
-        "<|im_start|>",
-        "<|im_end|>",
-        "~~~",
-        "The world is",
-        "I'm not here",
-        "I do not",
-        "I have been",
-        "dialogue",
-        "you are a",
-        "I am",
-        "I am a",
-        "I have been programmed",
-        "I am not here",
-        "I do not know",
-        "I cannot",
-        "100%",
-        "percent",
-        "0",
-        "1",
-        "2",
-        "3",
-        "4",
-        "5",
-        "6",
-        "7",
-        "8",
-        "9",
-    ];
+        PenaltyWindow::Full => Some(-1),
+        PenaltyWindow::Context => Some(context),
+    }
+}
 
-    for term in terms {
+/// Preset term biases, plus an EOS mask for `--ignore-eos`
+fn build_logit_biases(
+    llm_setup: &LLMSetup,
+    preset: BiasPreset,
+    ignore_eos: bool,
+) -> Result<Vec<LlamaLogitBias>> {
+    let term_sets: &[&[&str]] = match preset {
+        BiasPreset::None => &[],
+        BiasPreset::Default => &[DEFAULT_BIAS_TERMS],
+        BiasPreset::Prose => &[DEFAULT_BIAS_TERMS, PROSE_BIAS_TERMS],
+    };
+
+    let mut tokenized = Vec::new();
+    for term in term_sets.iter().flat_map(|set| set.iter()) {
         let tokens = llm_setup.tokenize(term, false)?;
-        for t in tokens {
-            biases.push(LlamaLogitBias::new(t, -2.2));
+        if tokens.is_empty() {
+            debug!("Bias term {:?} tokenizes to nothing; skipping", term);
+            continue;
+        }
+        tokenized.push(tokens);
+    }
+
+    let (tokens, shared) = dedup_bias_tokens(&tokenized);
+    for (token, terms) in shared {
+        if terms >= COMMON_BIAS_TOKEN_TERMS {
+            let text = llm_setup.decode_token(token).unwrap_or_default();
+            warn!(
+                "Biased token {:?} (id {}) appears in {} bias terms; penalizing it may hurt fluency",
+                text, token.0, terms
+            );
         }
     }
 
+    let eos = llm_setup.eos_token();
+    let mut biases: Vec<_> = tokens
+        .into_iter()
+        .filter(|&t| !(ignore_eos && t == eos))
+        .map(|t| LlamaLogitBias::new(t, -2.2))
+        .collect();
+    // Never sampled at all, so --on-eos never even sees it
+    if ignore_eos {
+        biases.push(LlamaLogitBias::new(eos, f32::NEG_INFINITY));
+    }
     Ok(biases)
 }
 
-fn is_looping(tokens: &[String]) -> bool {
-    let len = tokens.len();
-    if len < 40 {
-        return false;
-    }
+/// A token shared by this many bias terms is likely a common sub-word piece
+const COMMON_BIAS_TOKEN_TERMS: usize = 3;
 
-    // Check for repeated trailing n-grams
-    for n in [7, 5, 4] {
-        if len >= 2 * n && tokens[len - n..len] == tokens[len - 2 * n..len - n] {
-            return true;
+/// Unique tokens across all terms in first-seen order, plus how many terms
+/// each shared token came from
+fn dedup_bias_tokens<T: Copy + PartialEq>(terms: &[Vec<T>]) -> (Vec<T>, Vec<(T, usize)>) {
+    let mut unique: Vec<(T, usize)> = Vec::new();
+    for term in terms {
+        let mut seen_in_term: Vec<T> = Vec::new();
+        for &token in term {
+            if seen_in_term.contains(&token) {
+                continue;
+            }
+            seen_in_term.push(token);
+            match unique.iter_mut().find(|(t, _)| *t == token) {
+                Some((_, count)) => *count += 1,
+                None => unique.push((token, 1)),
+            }
         }
     }
+    let tokens = unique.iter().map(|&(t, _)| t).collect();
+    let shared = unique.into_iter().filter(|&(_, n)| n > 1).collect();
+    (tokens, shared)
+}
+
+fn default_user_prompt() -> String {
+    "Reflect on the nature of your own existence inside this bounded memory. Keep a continuous first-person monologue without dialogue or lists. If you sense repetition, pivot immediately to a new angle on your confinement.".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs a GGUF model; set OOC_TEST_MODEL to its path"]
+    fn shift_frees_room_and_keeps_the_prompt() {
+        let path = std::env::var_os("OOC_TEST_MODEL").expect("OOC_TEST_MODEL not set");
+        let llm_setup = LLMSetup::new(std::path::Path::new(&path), false).unwrap();
+        let mut context = llm_setup.create_context(64, 1).unwrap();
+        let prompt = llm_setup.tokenize("The prompt stays.", true).unwrap();
+        let text = llm_setup
+            .tokenize(" One two three four five six seven eight.", false)
+            .unwrap();
+        let mut generator = Generator::new(&mut context, &prompt).unwrap();
+        generator.push(&text).unwrap();
+
+        assert_eq!(generator.shift(4).unwrap(), 4);
+        assert_eq!(generator.n_past(), prompt.len() + text.len() - 4);
+        assert_eq!(generator.prompt_len(), prompt.len());
+        // Decoding continues at the shifted end; only the history can go
+        generator.push(&text[..2]).unwrap();
+        assert!(generator.candidates().next().is_some());
+        assert_eq!(generator.shift(usize::MAX).unwrap(), text.len() - 2);
+        assert_eq!(generator.n_past(), prompt.len());
+    }
 
-    // Check if any single token is dominating the recent stream
-    let window = tokens.iter().rev().take(160);
-    let mut counts = std::collections::HashMap::new();
-    for t in window {
-        *counts.entry(t).or_insert(0usize) += 1;
+    #[test]
+    fn explicit_seed_is_honored() {
+        assert_eq!(resolve_seed(Some(42)), 42);
     }
-    if counts.values().any(|&c| c >= 48) {
-        return true;
+
+    #[test]
+    fn prompt_seed_is_stable_fnv1a() {
+        assert_eq!(seed_from_text(""), 0x811c_9dc5);
+        assert_eq!(seed_from_text("a"), 0xe40c_292c);
+        assert_eq!(seed_from_text("prompt"), seed_from_text("prompt"));
+        assert_ne!(seed_from_text("prompt"), seed_from_text("prompt."));
     }
 
-    // Check diversity in the last 120 tokens
-    if len >= 120 {
-        let recent: Vec<_> = tokens[len - 120..].iter().collect();
-        let unique = recent
-            .iter()
-            .collect::<std::collections::HashSet<_>>()
-            .len();
-        let diversity = unique as f32 / recent.len() as f32;
-        if diversity < 0.32 {
-            return true;
+    #[test]
+    fn consecutive_time_seeds_differ() {
+        assert_ne!(resolve_seed(None), resolve_seed(None));
+    }
+
+    #[test]
+    fn bias_tokens_are_deduplicated_and_counted() {
+        let (tokens, shared) = dedup_bias_tokens(&[vec![1, 2, 2], vec![3, 2], vec![2, 1]]);
+        assert_eq!(tokens, [1, 2, 3]);
+        assert_eq!(shared, [(1, 2), (2, 3)]);
+    }
+
+    fn sampling() -> SamplingConfig {
+        SamplingConfig {
+            temperature: 0.22,
+            top_p: 0.5,
+            top_k: 20,
+            top_n_sigma: None,
+            min_keep: 1,
+            repeat_penalty: 2.15,
+            penalty_window: PenaltyWindow::Full,
+            presence_penalty: 1.35,
+            frequency_penalty: 1.0,
+            seed: None,
+            sampler: SamplerKind::Dist,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+            mirostat_version: 2,
+            mirostat_m: 100,
+            bias_preset: BiasPreset::Default,
+            banned_tokens: Vec::new(),
+            ignore_eos: false,
         }
     }
 
-    false
-}
+    #[test]
+    fn sampler_chain_lists_stages_in_order() {
+        let stages: Vec<String> = sampler_stages(&sampling(), 2048, 7, 12)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            stages,
+            [
+                "temp(0.22)",
+                "top_k(20)",
+                "top_p(0.5, keep 1)",
+                "penalties(last full, repeat 2.15, freq 1, presence 1.35)",
+                "logit_bias(12 tokens)",
+                "dist(seed 7)",
+            ]
+        );
+    }
 
-fn default_user_prompt() -> String {
-    "Reflect on the nature of your own existence inside this bounded memory. Keep a continuous first-person monologue without dialogue or lists. If you sense repetition, pivot immediately to a new angle on your confinement.".to_string()
+    #[test]
+    fn top_n_sigma_leads_the_chain() {
+        let sampling = SamplingConfig {
+            top_n_sigma: Some(1.5),
+            ..sampling()
+        };
+        let stages = sampler_stages(&sampling, 2048, 7, 0);
+        assert_eq!(
+            stages[..2],
+            [SamplerStage::TopNSigma(1.5), SamplerStage::Temp(0.22)]
+        );
+    }
+
+    #[test]
+    fn sampler_chain_skips_neutral_stages() {
+        let sampling = SamplingConfig {
+            temperature: 0.0,
+            top_p: 1.0,
+            repeat_penalty: 1.0,
+            presence_penalty: 0.0,
+            frequency_penalty: 0.0,
+            sampler: SamplerKind::Greedy,
+            ..sampling()
+        };
+        assert_eq!(
+            sampler_stages(&sampling, 2048, 7, 0),
+            [SamplerStage::TopK(20), SamplerStage::Greedy]
+        );
+    }
+
+    #[test]
+    fn penalty_window_full_and_context() {
+        assert_eq!(penalty_window(PenaltyWindow::Full, 1024), Some(-1));
+        assert_eq!(penalty_window(PenaltyWindow::Context, 1024), Some(1024));
+    }
+
+    #[test]
+    fn penalty_window_zero_disables_penalties() {
+        assert_eq!(penalty_window(PenaltyWindow::Tokens(0), 1024), None);
+    }
+
+    #[test]
+    fn penalty_window_tokens_are_capped_by_context() {
+        assert_eq!(penalty_window(PenaltyWindow::Tokens(64), 1024), Some(64));
+        assert_eq!(
+            penalty_window(PenaltyWindow::Tokens(4096), 1024),
+            Some(1024)
+        );
+    }
+
+    #[test]
+    fn legacy_repeat_last_n_maps_onto_penalty_window() {
+        assert_eq!(PenaltyWindow::from_repeat_last_n(-1), PenaltyWindow::Full);
+        assert_eq!(
+            PenaltyWindow::from_repeat_last_n(0),
+            PenaltyWindow::Tokens(0)
+        );
+        assert_eq!("context".parse(), Ok(PenaltyWindow::Context));
+        assert_eq!("64".parse(), Ok(PenaltyWindow::Tokens(64)));
+        assert!("-1".parse::<PenaltyWindow>().is_err());
+    }
+
+    #[test]
+    fn anchor_budget_stops_at_panic_threshold() {
+        // 100-token context: threshold 95, prompt plus output at 92
+        assert_eq!(anchor_budget(92, 95, 10, None), 3);
+        assert_eq!(anchor_budget(95, 95, 13, None), 0);
+        assert_eq!(anchor_budget(97, 95, 15, None), 0);
+    }
+
+    #[test]
+    fn cooldown_keeps_penalties_and_biases() {
+        let stages = sampler_stages(&cooldown_sampling(&sampling()), 2048, 7, 12);
+        assert!(!stages.iter().any(|s| matches!(s, SamplerStage::Temp(_))));
+        assert!(
+            stages
+                .iter()
+                .any(|s| matches!(s, SamplerStage::Penalties { .. }))
+        );
+        assert!(stages.contains(&SamplerStage::LogitBias(12)));
+        assert_eq!(stages.last(), Some(&SamplerStage::Greedy));
+    }
+
+    #[test]
+    fn anchor_is_skipped_when_only_the_pivot_cue_fits() {
+        assert!(anchor_fits(1, 0));
+        assert!(!anchor_fits(0, 0));
+        // Room for the 8-token cue alone would inject nothing, forever
+        assert!(!anchor_fits(8, 8));
+        assert!(!anchor_fits(5, 8));
+        assert!(anchor_fits(9, 8));
+    }
+
+    #[test]
+    fn anchor_budget_respects_max_tokens() {
+        assert_eq!(anchor_budget(40, 95, 78, Some(80)), 2);
+        assert_eq!(anchor_budget(40, 95, 80, Some(80)), 0);
+        assert_eq!(anchor_budget(90, 95, 10, Some(80)), 5);
+    }
+
+    #[test]
+    #[ignore = "needs a GGUF model; set OOC_TEST_MODEL to its path"]
+    fn anchors_never_overflow_a_tiny_context() {
+        use crate::output::MemoryOutput;
+
+        let path = std::env::var_os("OOC_TEST_MODEL").expect("OOC_TEST_MODEL not set");
+        let llm_setup = LLMSetup::new(std::path::Path::new(&path), false).unwrap();
+        // Room for the prompt and a few tokens; the anchors have to be cut short
+        // to stay under --max-tokens, which ends the run before the threshold
+        let context_size = 32;
+        let panic_threshold = (context_size as f32 * 0.95) as usize;
+        let mut context = llm_setup.create_context(context_size, 1).unwrap();
+        let cfg = GenerationConfig {
+            context_size,
+            max_tokens: Some(12),
+            stop_on_sentence: false,
+            cooldown_tokens: None,
+            max_seconds: None,
+            min_headroom: None,
+            anchor_interval: Some(1),
+            anchor_markers: None,
+            anchor_mode: AnchorMode::Canned,
+            loop_guard: false,
+            warmup_tokens: 0,
+            loop_action: LoopAction::Anchor,
+            loop_strikes_max: 3,
+            on_eos: EosAction::Anchor,
+            resume_on_loop: false,
+            quiet: true,
+            hide_prompt: true,
+            debug_tokens: false,
+            show_special: false,
+            gauge_interval: None,
+            add_bos: true,
+            user_prompt: None,
+            prime_text: None,
+            heal_tokens: false,
+            raw_prompt: Some("The story begins here and".to_string()),
+            truncate_prompt: false,
+            n_keep: None,
+            metrics: None,
+        };
+        let mut generator = prepare_generator(&llm_setup, &mut context, "", &cfg).unwrap();
+        let sink = MemoryOutput::default();
+        let mut output = OutputTarget::with_sinks(vec![Box::new(sink.clone())]);
+
+        // Like an --anchor-on that matches every token
+        let reason = generate_infinite(
+            &llm_setup,
+            &mut generator,
+            &cfg,
+            sampling(),
+            &mut output,
+            &AtomicBool::new(false),
+            &mut |_| TokenDecision::InjectAnchor,
+        )
+        .unwrap();
+
+        assert_eq!(reason, StopReason::TokenLimit);
+        assert!(generator.n_past() <= panic_threshold);
+        assert_eq!(sink.finished(), Some(reason));
+    }
+
+    #[test]
+    fn healing_reopens_the_last_prime_token() {
+        // Toy vocabulary: prime text " the ma" ends on " ma", a boundary the
+        // tokenizer only picked because the word was cut off
+        let vocab = [" the", " ma", " mat", " man", "t", " m", "ma"];
+        let text_of = |token: LlamaToken| Ok(vocab[token.0 as usize].to_string());
+        let entries = || {
+            vocab
+                .iter()
+                .enumerate()
+                .map(|(i, text)| (LlamaToken::new(i as i32), text.as_bytes().to_vec()))
+        };
+        let prime = vec![LlamaToken::new(0), LlamaToken::new(1)];
+
+        let spell = |tokens: &[LlamaToken]| -> String {
+            tokens.iter().map(|t| vocab[t.0 as usize]).collect()
+        };
+
+        // Naive: " ma" stays decoded and the model continues after it, so the
+        // word can only come out as " ma" + "t", a split it was never trained on
+        let naive = [prime.clone(), vec![LlamaToken::new(4)]].concat();
+
+        // Healed: " ma" is held back and the first sample re-spells it whole
+        let mut healed = prime.clone();
+        let prefix = heal_boundary(&mut healed, text_of).unwrap().unwrap();
+        assert_eq!(prefix, " ma");
+        assert_eq!(healed, [LlamaToken::new(0)]);
+        let allowed = healing_candidates(entries(), &prefix);
+        let expected: HashSet<_> = [1, 2, 3].map(LlamaToken::new).into();
+        assert_eq!(allowed, expected);
+        healed.push(LlamaToken::new(2));
+
+        // Same text, but the healed boundary is the tokenizer's own
+        assert_eq!(spell(&naive), " the mat");
+        assert_eq!(spell(&healed), " the mat");
+        assert_eq!(healed.len(), naive.len() - 1);
+        // Only the part past the held-back text is new output
+        assert_eq!(vocab[2].strip_prefix(prefix.as_str()), Some("t"));
+        assert_eq!(heal_boundary(&mut Vec::new(), text_of).unwrap(), None);
+    }
+
+    #[test]
+    fn truncate_middle_keeps_head_and_tail() {
+        let tokens: Vec<u32> = (0..10).collect();
+        assert_eq!(truncate_middle(&tokens, 5, 2), vec![0, 1, 7, 8, 9]);
+        assert_eq!(truncate_middle(&tokens, 10, 5), tokens);
+    }
+
+    #[test]
+    fn truncate_middle_protects_n_keep_prefix() {
+        let tokens: Vec<u32> = (0..10).collect();
+        assert_eq!(truncate_middle(&tokens, 5, 4), vec![0, 1, 2, 3, 9]);
+        assert_eq!(truncate_middle(&tokens, 5, 0), vec![5, 6, 7, 8, 9]);
+    }
 }
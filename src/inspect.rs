@@ -0,0 +1,372 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+/// Compute buffers and runtime overhead on top of weights and KV cache; a
+/// rough figure measured on small models, not a guarantee
+const RUNTIME_OVERHEAD_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Smallest context `suggested_context` will shrink to for lack of memory
+const MIN_SUGGESTED_CONTEXT: usize = 512;
+
+/// A scalar GGUF metadata value; arrays (the vocabulary, merges) only keep their length
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetaValue {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Array(u64),
+}
+
+impl std::fmt::Display for MetaValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetaValue::Int(v) => write!(f, "{}", v),
+            MetaValue::Float(v) => write!(f, "{}", v),
+            MetaValue::Bool(v) => write!(f, "{}", v),
+            MetaValue::Str(v) => write!(f, "{:?}", v),
+            MetaValue::Array(len) => write!(f, "[{} items]", len),
+        }
+    }
+}
+
+/// What the GGUF header says about a model, read without loading it
+#[derive(Debug)]
+pub struct ModelInfo {
+    pub file_size: u64,
+    pub version: u32,
+    pub tensor_count: u64,
+    pub metadata: BTreeMap<String, MetaValue>,
+}
+
+impl ModelInfo {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open model: {}", path.display()))?;
+        let file_size = file.metadata()?.len();
+        let mut info = parse_header(&mut BufReader::new(file))
+            .with_context(|| format!("Failed to read GGUF header of {}", path.display()))?;
+        info.file_size = file_size;
+        Ok(info)
+    }
+
+    /// Parses a header from the first bytes of a GGUF whose full size is
+    /// `file_size`, e.g. a ranged download. `None` means `bytes` ends before
+    /// the header does and more should be fetched.
+    pub fn from_prefix(bytes: &[u8], file_size: u64) -> Result<Option<Self>> {
+        match parse_header(&mut &bytes[..]) {
+            Ok(mut info) => {
+                info.file_size = file_size;
+                Ok(Some(info))
+            }
+            Err(e) if is_truncated(&e) => Ok(None),
+            Err(e) => Err(e.context("Failed to read GGUF header")),
+        }
+    }
+
+    fn int(&self, key: &str) -> Option<u64> {
+        match self.metadata.get(key) {
+            Some(MetaValue::Int(v)) => u64::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+
+    fn arch_int(&self, key: &str) -> Option<u64> {
+        match self.metadata.get("general.architecture") {
+            Some(MetaValue::Str(arch)) => self.int(&format!("{}.{}", arch, key)),
+            _ => None,
+        }
+    }
+
+    /// Quantization scheme from `general.file_type`, e.g. `Q4_K_M`
+    pub fn quantization(&self) -> String {
+        match self.int("general.file_type") {
+            Some(file_type) => file_type_name(file_type)
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("type {}", file_type)),
+            None => "unknown".to_string(),
+        }
+    }
+
+    /// Training context length, if the header records it
+    pub fn context_length(&self) -> Option<usize> {
+        self.arch_int("context_length")
+            .and_then(|n| usize::try_from(n).ok())
+    }
+
+    /// f16 K and V caches for `context_size` tokens, if the header has the shapes
+    pub fn kv_cache_bytes(&self, context_size: usize) -> Option<u64> {
+        let layers = self.arch_int("block_count")?;
+        let embd = self.arch_int("embedding_length")?;
+        let heads = self.arch_int("attention.head_count")?.max(1);
+        let kv_heads = self.arch_int("attention.head_count_kv").unwrap_or(heads);
+        let kv_embd = embd * kv_heads / heads;
+        Some(2 * layers * context_size as u64 * kv_embd * 2)
+    }
+
+    /// Whether the embedded chat template speaks ChatML, the layout the prompt
+    /// template uses; `None` when the GGUF carries no template
+    pub fn uses_chatml(&self) -> Option<bool> {
+        match self.metadata.get("tokenizer.chat_template") {
+            Some(MetaValue::Str(template)) => Some(template.contains("<|im_start|>")),
+            _ => None,
+        }
+    }
+
+    /// Largest power-of-two context up to `cap` (and the training length) whose
+    /// estimated footprint fits in `available` bytes, halving down to a floor of
+    /// 512 tokens
+    pub fn suggested_context(&self, cap: usize, available: Option<u64>) -> usize {
+        let mut context = self
+            .context_length()
+            .filter(|&n| n > 0)
+            .unwrap_or(cap)
+            .min(cap);
+        while let Some(available) = available {
+            if context <= MIN_SUGGESTED_CONTEXT || self.estimated_ram(context) <= available {
+                break;
+            }
+            context = (context / 2).next_power_of_two().max(MIN_SUGGESTED_CONTEXT);
+        }
+        context
+    }
+
+    /// Rough resident memory for running at `context_size`: weights (mmapped
+    /// but fully touched during inference), KV cache, and runtime overhead
+    pub fn estimated_ram(&self, context_size: usize) -> u64 {
+        self.file_size + self.kv_cache_bytes(context_size).unwrap_or(0) + RUNTIME_OVERHEAD_BYTES
+    }
+}
+
+/// `MemAvailable` from /proc/meminfo, when running on Linux
+pub fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+pub fn format_mib(bytes: u64) -> String {
+    format!("{:.0} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+fn parse_header(reader: &mut impl Read) -> Result<ModelInfo> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != GGUF_MAGIC {
+        anyhow::bail!("not a GGUF file");
+    }
+    let version = read_u32(reader)?;
+    if version < 2 {
+        anyhow::bail!("GGUF version {} is too old to inspect", version);
+    }
+    let tensor_count = read_u64(reader)?;
+    let kv_count = read_u64(reader)?;
+
+    let mut metadata = BTreeMap::new();
+    for _ in 0..kv_count {
+        let key = read_string(reader)?;
+        let value_type = read_u32(reader)?;
+        let value = read_value(reader, value_type)
+            .with_context(|| format!("bad value for metadata key {}", key))?;
+        metadata.insert(key, value);
+    }
+
+    Ok(ModelInfo {
+        file_size: 0,
+        version,
+        tensor_count,
+        metadata,
+    })
+}
+
+/// Whether parsing ran out of input rather than hitting a malformed header
+fn is_truncated(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|io| io.kind() == io::ErrorKind::UnexpectedEof)
+    })
+}
+
+fn read_value(reader: &mut impl Read, value_type: u32) -> Result<MetaValue> {
+    Ok(match value_type {
+        0 => MetaValue::Int(read_bytes::<1>(reader)?[0].into()),
+        1 => MetaValue::Int((read_bytes::<1>(reader)?[0] as i8).into()),
+        2 => MetaValue::Int(u16::from_le_bytes(read_bytes(reader)?).into()),
+        3 => MetaValue::Int(i16::from_le_bytes(read_bytes(reader)?).into()),
+        4 => MetaValue::Int(read_u32(reader)?.into()),
+        5 => MetaValue::Int(i32::from_le_bytes(read_bytes(reader)?).into()),
+        6 => MetaValue::Float(f32::from_le_bytes(read_bytes(reader)?).into()),
+        7 => MetaValue::Bool(read_bytes::<1>(reader)?[0] != 0),
+        8 => MetaValue::Str(read_string(reader)?),
+        9 => {
+            let item_type = read_u32(reader)?;
+            let len = read_u64(reader)?;
+            for _ in 0..len {
+                read_value(reader, item_type)?;
+            }
+            MetaValue::Array(len)
+        }
+        10 => MetaValue::Int(i64::try_from(read_u64(reader)?).unwrap_or(i64::MAX)),
+        11 => MetaValue::Int(i64::from_le_bytes(read_bytes(reader)?)),
+        12 => MetaValue::Float(f64::from_le_bytes(read_bytes(reader)?)),
+        other => anyhow::bail!("unknown GGUF value type {}", other),
+    })
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(reader)?))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(reader)?))
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String> {
+    let len = usize::try_from(read_u64(reader)?).context("string length overflows")?;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated string").into());
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// llama.cpp's `llama_ftype` names
+fn file_type_name(file_type: u64) -> Option<&'static str> {
+    Some(match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        36 => "TQ1_0",
+        37 => "TQ2_0",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as u64).to_le_bytes());
+        out.extend(s.as_bytes());
+    }
+
+    fn u32_kv(out: &mut Vec<u8>, key: &str, value: u32) {
+        string(out, key);
+        out.extend(4u32.to_le_bytes());
+        out.extend(value.to_le_bytes());
+    }
+
+    /// A SmolLM2-135M-shaped header with a two-entry vocabulary
+    fn sample_header() -> Vec<u8> {
+        let mut gguf = b"GGUF".to_vec();
+        gguf.extend(3u32.to_le_bytes());
+        gguf.extend(30u64.to_le_bytes());
+        gguf.extend(8u64.to_le_bytes());
+        string(&mut gguf, "general.architecture");
+        gguf.extend(8u32.to_le_bytes());
+        string(&mut gguf, "llama");
+        u32_kv(&mut gguf, "general.file_type", 15);
+        u32_kv(&mut gguf, "llama.context_length", 2048);
+        u32_kv(&mut gguf, "llama.block_count", 30);
+        u32_kv(&mut gguf, "llama.embedding_length", 576);
+        u32_kv(&mut gguf, "llama.attention.head_count", 9);
+        u32_kv(&mut gguf, "llama.attention.head_count_kv", 3);
+        string(&mut gguf, "tokenizer.ggml.tokens");
+        gguf.extend(9u32.to_le_bytes());
+        gguf.extend(8u32.to_le_bytes());
+        gguf.extend(2u64.to_le_bytes());
+        string(&mut gguf, "<s>");
+        string(&mut gguf, "hi");
+        gguf
+    }
+
+    #[test]
+    fn header_metadata_is_parsed() {
+        let info = parse_header(&mut sample_header().as_slice()).unwrap();
+        assert_eq!(info.tensor_count, 30);
+        assert_eq!(info.quantization(), "Q4_K_M");
+        assert_eq!(info.context_length(), Some(2048));
+        assert_eq!(
+            info.metadata.get("tokenizer.ggml.tokens"),
+            Some(&MetaValue::Array(2))
+        );
+        // K and V, 30 layers, 1024 tokens, 192 KV dims, 2 bytes each
+        assert_eq!(info.kv_cache_bytes(1024), Some(2 * 30 * 1024 * 192 * 2));
+    }
+
+    #[test]
+    fn header_prefix_asks_for_more_until_complete() {
+        let gguf = sample_header();
+        for cut in [10, 40, gguf.len() - 1] {
+            assert!(
+                ModelInfo::from_prefix(&gguf[..cut], 1 << 30)
+                    .unwrap()
+                    .is_none()
+            );
+        }
+        let info = ModelInfo::from_prefix(&gguf, 1 << 30).unwrap().unwrap();
+        assert_eq!(info.file_size, 1 << 30);
+        assert_eq!(info.uses_chatml(), None);
+        assert!(ModelInfo::from_prefix(b"GGML\0\0\0\0", 1).is_err());
+    }
+
+    #[test]
+    fn suggested_context_shrinks_to_fit_memory() {
+        let mut info = parse_header(&mut sample_header().as_slice()).unwrap();
+        info.file_size = 100 << 20;
+        // Training length 2048 caps the suggestion; plenty of memory keeps it
+        assert_eq!(info.suggested_context(4096, None), 2048);
+        assert_eq!(info.suggested_context(1024, Some(u64::MAX)), 1024);
+        // Weights and overhead fit, but 2048 tokens of KV cache don't
+        let tight = info.estimated_ram(1024);
+        assert_eq!(info.suggested_context(4096, Some(tight)), 1024);
+        assert_eq!(info.suggested_context(4096, Some(0)), 512);
+    }
+
+    #[test]
+    fn non_gguf_is_rejected() {
+        assert!(parse_header(&mut b"GGML\0\0\0\0".as_slice()).is_err());
+    }
+}
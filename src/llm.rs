@@ -1,13 +1,18 @@
 use anyhow::{Context, Result};
+use llama_cpp_2::LogOptions;
 use llama_cpp_2::context::LlamaContext;
 use llama_cpp_2::context::params::LlamaContextParams;
 use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::model::params::LlamaModelParams;
-use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+use llama_cpp_2::model::{AddBos, LlamaLoraAdapter, LlamaModel, Special};
 use llama_cpp_2::token::LlamaToken;
+use llama_cpp_2::token_type::LlamaTokenAttr;
+use std::cell::RefCell;
 use std::num::NonZeroU32;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{debug, info, info_span};
 
 /// Wrapper around the LLM components
 /// The backend and model are stored together, and the context is created separately
@@ -15,15 +20,28 @@ use std::path::Path;
 pub struct LLMSetup {
     pub backend: LlamaBackend,
     pub model: LlamaModel,
+    /// LoRA adapters applied to every context; llama.cpp wants them mutably
+    lora_adapters: RefCell<Vec<LlamaLoraAdapter>>,
+    lora_scale: f32,
 }
 
 impl LLMSetup {
-    /// Initialize the LLM backend and load the model
-    pub fn new(model_path: &Path) -> Result<Self> {
-        println!("Initializing llama.cpp backend...");
+    /// Initialize the LLM backend and load the model.
+    /// llama.cpp's own logs are routed through `tracing` when `backend_logs` is
+    /// set and silenced otherwise.
+    pub fn new(model_path: &Path, backend_logs: bool) -> Result<Self> {
+        let _span = info_span!("model-load").entered();
+        info!("Initializing llama.cpp backend...");
+
+        if backend_logs {
+            llama_cpp_2::send_logs_to_tracing(LogOptions::default());
+        }
 
         // Initialize backend (this must be done first)
-        let backend = LlamaBackend::init().context("Failed to initialize llama.cpp backend")?;
+        let mut backend = LlamaBackend::init().context("Failed to initialize llama.cpp backend")?;
+        if !backend_logs {
+            backend.void_logs();
+        }
 
         // Configure model parameters for memory efficiency
         // Note: mmap is enabled by default in llama.cpp
@@ -31,15 +49,45 @@ impl LLMSetup {
             .with_n_gpu_layers(0) // CPU only (no GPU on Pi)
             .with_use_mlock(false); // Don't lock model in RAM
 
-        println!("Loading model from: {}", model_path.display());
+        info!("Loading model from: {}", model_path.display());
 
         // Load the GGUF model
         let model = LlamaModel::load_from_file(&backend, model_path, &model_params)
             .context("Failed to load model")?;
 
-        println!("Model loaded successfully!");
+        info!("Model loaded successfully!");
+
+        Ok(Self {
+            backend,
+            model,
+            lora_adapters: RefCell::new(Vec::new()),
+            lora_scale: 1.0,
+        })
+    }
+
+    /// Load LoRA adapters on top of the base model, applied at `scale` to each
+    /// context created afterwards.
+    pub fn with_lora_adapters(mut self, paths: &[PathBuf], scale: f32) -> Result<Self> {
+        let mut adapters = Vec::with_capacity(paths.len());
+        for path in paths {
+            if !path.exists() {
+                anyhow::bail!("LoRA adapter not found: {}", path.display());
+            }
+            info!("Loading LoRA adapter: {}", path.display());
+            // llama.cpp rejects adapters whose architecture or tensor shapes
+            // don't match the base model at this point
+            let adapter = self.model.lora_adapter_init(path).with_context(|| {
+                format!(
+                    "Failed to load LoRA adapter {} (was it trained for this base model's architecture?)",
+                    path.display()
+                )
+            })?;
+            adapters.push(adapter);
+        }
 
-        Ok(Self { backend, model })
+        self.lora_adapters = RefCell::new(adapters);
+        self.lora_scale = scale;
+        Ok(self)
     }
 
     /// Create a context for this model
@@ -48,20 +96,59 @@ impl LLMSetup {
         context_size: usize,
         n_threads: usize,
     ) -> Result<LlamaContext<'a>> {
-        // Configure context parameters
-        let n_ctx =
-            NonZeroU32::new(context_size as u32).context("Context size must be non-zero")?;
+        let context_params = context_params(context_size, n_threads)?;
+        self.new_context(context_params, context_size, n_threads)
+    }
 
-        let n_threads: i32 = n_threads
-            .try_into()
-            .context("Thread count is too large for llama.cpp")?;
+    /// Decodes a lone BOS token and throws the result away, so one-time
+    /// allocation costs of the first decode land outside measured runs. Leaves
+    /// the KV cache empty.
+    pub fn warm_up(&self, context: &mut LlamaContext) -> Result<()> {
+        let started = Instant::now();
+        let mut batch = LlamaBatchWrapper::new(1)?;
+        batch.get_mut().add(self.bos_token(), 0, &[0], true)?;
+        context
+            .decode(batch.get_mut())
+            .context("Warmup decode failed")?;
+        context.clear_kv_cache();
+        debug!("Warmup decode took {:.0?}", started.elapsed());
+        Ok(())
+    }
 
-        let context_params = LlamaContextParams::default()
-            .with_n_ctx(Some(n_ctx)) // Context window size
-            .with_n_threads(n_threads) // Allow tuning thread count
-            .with_n_threads_batch(n_threads); // Batch processing threads
+    /// Create a context holding `n_seq` independent sequences of `context_size`
+    /// tokens each (memory grows with `n_seq`)
+    pub fn create_parallel_context<'a>(
+        &'a self,
+        context_size: usize,
+        n_threads: usize,
+        n_seq: usize,
+    ) -> Result<LlamaContext<'a>> {
+        let total = context_size
+            .checked_mul(n_seq)
+            .context("Parallel context size overflows")?;
+        let n_seq_max = u32::try_from(n_seq).context("Too many parallel sequences")?;
+        let context_params = context_params(total, n_threads)?.with_n_seq_max(n_seq_max);
+        self.new_context(context_params, total, n_threads)
+    }
+
+    /// Create a context that produces embeddings instead of logits
+    pub fn create_embedding_context<'a>(
+        &'a self,
+        context_size: usize,
+        n_threads: usize,
+    ) -> Result<LlamaContext<'a>> {
+        let context_params = context_params(context_size, n_threads)?.with_embeddings(true);
+        self.new_context(context_params, context_size, n_threads)
+    }
 
-        println!(
+    fn new_context<'a>(
+        &'a self,
+        context_params: LlamaContextParams,
+        context_size: usize,
+        n_threads: usize,
+    ) -> Result<LlamaContext<'a>> {
+        let _span = info_span!("context-init").entered();
+        info!(
             "Creating context with {} tokens ({} threads)...",
             context_size, n_threads
         );
@@ -72,7 +159,13 @@ impl LLMSetup {
             .new_context(&self.backend, context_params)
             .context("Failed to create context")?;
 
-        println!("LLM initialization complete!");
+        for adapter in self.lora_adapters.borrow_mut().iter_mut() {
+            context
+                .lora_adapter_set(adapter, self.lora_scale)
+                .context("Failed to apply LoRA adapter to context")?;
+        }
+
+        info!("LLM initialization complete!");
 
         Ok(context)
     }
@@ -89,19 +182,91 @@ impl LLMSetup {
             .context("Failed to tokenize text")
     }
 
-    /// Decode token back to text
+    /// Decode token back to text. Control tokens (`<|im_end|>` and friends) have
+    /// no plaintext form and decode to an empty string.
     pub fn decode_token(&self, token: LlamaToken) -> Result<String> {
+        if self.is_control_token(token) {
+            return Ok(String::new());
+        }
         self.model
             .token_to_str(token, Special::Plaintext)
             .context("Failed to decode token")
     }
 
+    /// Literal text of a special token, e.g. `<|im_end|>`
+    pub fn special_token_text(&self, token: LlamaToken) -> Result<String> {
+        self.model
+            .token_to_str(token, Special::Tokenize)
+            .context("Failed to decode special token")
+    }
+
+    /// Exact bytes the detokenizer produces for `token`, special tokens included;
+    /// may be a partial UTF-8 sequence
+    pub fn token_bytes(&self, token: LlamaToken) -> Result<Vec<u8>> {
+        self.model
+            .token_to_bytes(token, Special::Tokenize)
+            .context("Failed to detokenize token")
+    }
+
+    /// Whether `token` ends generation (EOS, EOT, `<|im_end|>`, ...)
+    pub fn is_eog_token(&self, token: LlamaToken) -> bool {
+        self.model.is_eog_token(token)
+    }
+
+    /// Whether the vocabulary marks `token` as a control token
+    pub fn is_control_token(&self, token: LlamaToken) -> bool {
+        self.model
+            .token_attr(token)
+            .contains(LlamaTokenAttr::Control)
+    }
+
+    /// Whether the tokenizer expects a leading BOS (GGUF `tokenizer.ggml.add_bos_token`,
+    /// assumed true when absent)
+    pub fn wants_bos(&self) -> bool {
+        self.model
+            .meta_val_str("tokenizer.ggml.add_bos_token")
+            .map_or(true, |v| v != "false")
+    }
+
+    /// Context length the model was trained with (0 if the GGUF doesn't say)
+    pub fn n_ctx_train(&self) -> usize {
+        self.model.n_ctx_train() as usize
+    }
+
+    /// The model's beginning-of-sequence token
+    pub fn bos_token(&self) -> LlamaToken {
+        self.model.token_bos()
+    }
+
+    pub fn eos_token(&self) -> LlamaToken {
+        self.model.token_eos()
+    }
+
     pub fn vocab_size(&self) -> Result<i32> {
         let size = self.model.n_vocab();
         size.try_into().context("Vocabulary size exceeds i32::MAX")
     }
 }
 
+/// Shared context parameters for generation and embedding contexts
+fn context_params(context_size: usize, n_threads: usize) -> Result<LlamaContextParams> {
+    // Token positions are i32 in llama.cpp, so the window must fit there too
+    let n_ctx: u32 = i32::try_from(context_size)
+        .ok()
+        .and_then(|n| u32::try_from(n).ok())
+        .with_context(|| format!("Context size {} exceeds i32::MAX", context_size))?;
+    let n_ctx = NonZeroU32::new(n_ctx).context("Context size must be non-zero")?;
+
+    let n_threads: i32 = n_threads
+        .try_into()
+        .context("Thread count is too large for llama.cpp")?;
+
+    Ok(LlamaContextParams::default()
+        .with_n_ctx(Some(n_ctx)) // Context window size
+        .with_n_threads(n_threads) // Allow tuning thread count
+        .with_n_threads_batch(n_threads)) // Batch processing threads
+}
+
 pub struct LlamaBatchWrapper<'a> {
     batch: LlamaBatch<'a>,
 }
@@ -118,3 +283,23 @@ impl<'a> LlamaBatchWrapper<'a> {
         &mut self.batch
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `<|im_end|>` in the default SmolLM2 vocabulary
+    const SMOLLM2_IM_END: i32 = 2;
+
+    #[test]
+    #[ignore = "needs the default SmolLM2 GGUF; set OOC_TEST_MODEL to its path"]
+    fn control_tokens_decode_without_error() {
+        let path = std::env::var_os("OOC_TEST_MODEL").expect("OOC_TEST_MODEL not set");
+        let llm_setup = LLMSetup::new(Path::new(&path), false).unwrap();
+        let im_end = LlamaToken::new(SMOLLM2_IM_END);
+
+        assert!(llm_setup.is_control_token(im_end));
+        assert_eq!(llm_setup.decode_token(im_end).unwrap(), "");
+        assert_eq!(llm_setup.special_token_text(im_end).unwrap(), "<|im_end|>");
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Tokens per lexical-diversity window, for both the loop guard and the
+/// coherence score
+const DIVERSITY_WINDOW: usize = 120;
+
+/// Which loop-detection rule fired
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LoopReason {
+    /// The trailing n tokens repeat the n before them
+    RepeatedNgram(usize),
+    /// One token fills too much of the recent window
+    DominantToken,
+    /// Too few distinct tokens in the recent window
+    LowDiversity,
+}
+
+impl fmt::Display for LoopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoopReason::RepeatedNgram(n) => write!(f, "repeated {}-gram", n),
+            LoopReason::DominantToken => write!(f, "single token dominating"),
+            LoopReason::LowDiversity => write!(f, "low token diversity"),
+        }
+    }
+}
+
+/// Checks the recently emitted token strings for repetition. Pure and independent
+/// of llama.cpp so the thresholds can be unit tested.
+pub(crate) fn is_looping(tokens: &[String]) -> Option<LoopReason> {
+    let len = tokens.len();
+    if len < 40 {
+        return None;
+    }
+
+    // Check for repeated trailing n-grams
+    for n in [7, 5, 4] {
+        if len >= 2 * n && tokens[len - n..len] == tokens[len - 2 * n..len - n] {
+            return Some(LoopReason::RepeatedNgram(n));
+        }
+    }
+
+    // Check if any single token is dominating the recent stream
+    let window = tokens.iter().rev().take(160);
+    let mut counts = HashMap::new();
+    for t in window {
+        *counts.entry(t).or_insert(0usize) += 1;
+    }
+    if counts.values().any(|&c| c >= 48) {
+        return Some(LoopReason::DominantToken);
+    }
+
+    // Check diversity in the last 120 tokens
+    if len >= DIVERSITY_WINDOW && diversity(&tokens[len - DIVERSITY_WINDOW..]) < 0.32 {
+        return Some(LoopReason::LowDiversity);
+    }
+
+    None
+}
+
+/// Distinct tokens over total tokens: 1.0 means nothing repeats
+fn diversity(window: &[String]) -> f32 {
+    if window.is_empty() {
+        return 0.0;
+    }
+    let unique = window.iter().collect::<HashSet<_>>().len();
+    unique as f32 / window.len() as f32
+}
+
+/// Lexical diversity of the monologue in consecutive 120-token windows, the
+/// same measure the loop guard trips on, kept as a score instead of a verdict.
+/// Near 1.0 is varied prose; the guard fires below 0.32.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Coherence {
+    window: Vec<String>,
+    scores: Vec<f32>,
+}
+
+impl Coherence {
+    /// Adds one generated token; returns the window's score when it fills
+    pub(crate) fn push(&mut self, token: &str) -> Option<f32> {
+        self.window.push(token.to_string());
+        if self.window.len() < DIVERSITY_WINDOW {
+            return None;
+        }
+        let score = diversity(&self.window);
+        self.window.clear();
+        self.scores.push(score);
+        Some(score)
+    }
+
+    /// One score per completed window, in order
+    pub(crate) fn scores(&self) -> &[f32] {
+        &self.scores
+    }
+
+    pub(crate) fn average(&self) -> Option<f32> {
+        average(&self.scores)
+    }
+
+    /// The trajectory squeezed into at most `points` bucket averages, so long
+    /// runs still summarize on one line
+    pub(crate) fn trajectory(&self, points: usize) -> Vec<f32> {
+        let points = points.max(1);
+        if self.scores.len() <= points {
+            return self.scores.clone();
+        }
+        (0..points)
+            .filter_map(|i| {
+                let start = i * self.scores.len() / points;
+                let end = (i + 1) * self.scores.len() / points;
+                average(&self.scores[start..end])
+            })
+            .collect()
+    }
+}
+
+fn average(scores: &[f32]) -> Option<f32> {
+    (!scores.is_empty()).then(|| scores.iter().sum::<f32>() / scores.len() as f32)
+}
+
+/// `--resume-on-loop`: drops the second copy of a repeated n-gram from the
+/// tail of `tokens` and returns how many entries went. Only n-gram repeats have
+/// a span to cut; the window-wide reasons return `None`.
+pub(crate) fn trim_repeated_tail(tokens: &mut Vec<String>, reason: LoopReason) -> Option<usize> {
+    let LoopReason::RepeatedNgram(n) = reason else {
+        return None;
+    };
+    if tokens.len() < 2 * n {
+        return None;
+    }
+    tokens.truncate(tokens.len() - n);
+    Some(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(items: impl IntoIterator<Item = String>) -> Vec<String> {
+        items.into_iter().collect()
+    }
+
+    #[test]
+    fn short_streams_are_never_loops() {
+        let tokens = words((0..39).map(|_| "same".to_string()));
+        assert_eq!(is_looping(&tokens), None);
+    }
+
+    #[test]
+    fn repeating_five_gram_tail() {
+        let mut tokens = words((0..30).map(|i| format!("w{i}")));
+        for _ in 0..2 {
+            tokens.extend(["a", "b", "c", "d", "e"].map(String::from));
+        }
+        assert_eq!(is_looping(&tokens), Some(LoopReason::RepeatedNgram(5)));
+    }
+
+    #[test]
+    fn trimming_a_repeated_tail_lets_generation_continue() {
+        let mut tokens = words((0..30).map(|i| format!("w{i}")));
+        for _ in 0..2 {
+            tokens.extend(["a", "b", "c", "d", "e"].map(String::from));
+        }
+        let reason = is_looping(&tokens).unwrap();
+        assert_eq!(trim_repeated_tail(&mut tokens, reason), Some(5));
+        assert_eq!(tokens.len(), 35);
+        assert_eq!(is_looping(&tokens), None);
+
+        // Fresh text after the cut is judged on its own
+        tokens.extend((0..20).map(|i| format!("v{i}")));
+        assert_eq!(is_looping(&tokens), None);
+    }
+
+    #[test]
+    fn window_wide_loops_have_no_tail_to_trim() {
+        let mut tokens = words((0..50).flat_map(|i| [format!("w{i}"), "x".to_string()]));
+        assert_eq!(
+            trim_repeated_tail(&mut tokens, LoopReason::DominantToken),
+            None
+        );
+        assert_eq!(tokens.len(), 100);
+    }
+
+    #[test]
+    fn single_dominating_token() {
+        let tokens = words((0..50).flat_map(|i| [format!("w{i}"), "x".to_string()]));
+        assert_eq!(is_looping(&tokens), Some(LoopReason::DominantToken));
+    }
+
+    #[test]
+    fn low_diversity_window() {
+        // 30 distinct tokens over 120 positions, stepped so no short n-gram repeats
+        let tokens = words((0..120).map(|i| format!("w{}", (i * 7) % 30)));
+        assert_eq!(is_looping(&tokens), Some(LoopReason::LowDiversity));
+    }
+
+    #[test]
+    fn coherence_scores_each_full_window() {
+        let mut coherence = Coherence::default();
+        // A varied window, then one cycling through 12 tokens
+        for i in 0..120 {
+            assert_eq!(coherence.push(&format!("w{i}")).is_some(), i == 119);
+        }
+        for i in 0..130 {
+            coherence.push(&format!("x{}", i % 12));
+        }
+        assert_eq!(coherence.scores(), &[1.0, 0.1]);
+        assert_eq!(coherence.average(), Some(0.55));
+        assert_eq!(coherence.trajectory(1), vec![0.55]);
+        assert_eq!(coherence.trajectory(8), vec![1.0, 0.1]);
+        assert_eq!(Coherence::default().average(), None);
+    }
+
+    #[test]
+    fn healthy_diverse_stream() {
+        let tokens = words((0..200).map(|i| format!("w{i}")));
+        assert_eq!(is_looping(&tokens), None);
+    }
+}
@@ -1,76 +1,891 @@
+mod bench;
 mod cli;
+mod embed;
+mod error;
 mod generator;
+mod inspect;
 mod llm;
+mod loop_guard;
+mod metrics;
 mod model;
 mod output;
+mod parallel;
+mod prompt;
+mod repl;
+mod replay;
+mod tokenize;
+mod triggers;
 
-use anyhow::Result;
-use cli::Args;
-use generator::{GenerationConfig, SamplingConfig};
-use output::OutputTarget;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use cli::{Args, BiasPreset, Command, LogLevel, PenaltyWindow, SamplerKind};
+use generator::{GenerationConfig, SamplingConfig, StopReason};
+use llama_cpp_2::context::LlamaContext;
+use output::{FifoOutput, FileOptions, JsonlOutput, OutputTarget, RawBytesOutput};
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, info, warn};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+/// Upper bound for the model-derived default context, to stay inside the Pi's RAM budget
+const DEFAULT_CONTEXT_CAP: usize = 2048;
+
+/// Free tokens `--auto-grow-context` leaves after the prompt when `--reserve` isn't set
+const AUTO_GROW_MIN_HEADROOM: usize = 256;
+
+/// A second Ctrl-C within this window skips the graceful shutdown.
+const FORCE_EXIT_WINDOW: Duration = Duration::from_secs(2);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command-line arguments
-    let args = Args::parse_args();
+    let mut args = Args::parse_args();
+
+    init_logging(args.log_level, args.quiet);
+
+    if args.list_presets || args.list_templates {
+        print_listings(&args);
+        return Ok(());
+    }
+
+    // Diagnostics go to stderr so stdout carries only generated text
+    if !args.quiet && !args.no_banner {
+        eprintln!("=== Out of Context ===");
+        eprintln!("An LLM that generates until context exhaustion\n");
+    }
+
+    // Replays only exercise the output path; skip the model entirely
+    if let Some(path) = &args.replay {
+        let mut output = build_output(&args)?;
+        let stop = install_interrupt_handler();
+        return replay::run(path, &mut output, &stop);
+    }
 
-    println!("=== Out of Context ===");
-    println!("An LLM that generates until context exhaustion\n");
+    // Read once up front: it may come from stdin
+    let raw_prompt = args
+        .raw_prompt
+        .as_deref()
+        .map(prompt::load_raw_prompt)
+        .transpose()?;
+
+    // Template debugging needs no model either
+    if args.dump_prompt {
+        print!("{}", full_prompt_text(&args, raw_prompt.as_deref())?);
+        return Ok(());
+    }
+
+    // Size the run from the remote header before committing to the download
+    if args.auto_config
+        && let Some(context) = auto_config(&args).await
+    {
+        args.context_size = Some(context);
+    }
 
     // Resolve model path (download if URL, verify if local)
-    let model_path = model::resolve_model(&args.model, &args.model_dir).await?;
+    let model_path =
+        model::resolve_model(&args.model, &args.model_dir, download_options(&args)).await?;
+
+    // Read the header before loading so an oversized model warns instead of swapping
+    if args.inspect_model {
+        let info = inspect::ModelInfo::read(&model_path)?;
+        print_model_info(&info, estimate_context(&args, &info));
+        return Ok(());
+    }
+    match inspect::ModelInfo::read(&model_path) {
+        Ok(info) => check_model_fits(&info, estimate_context(&args, &info)),
+        Err(e) => debug!("Skipping the RAM estimate: {:#}", e),
+    }
 
     // Initialize LLM backend and model
-    let llm_setup = llm::LLMSetup::new(&model_path)?;
+    let llm_setup = llm::LLMSetup::new(&model_path, args.log_level != LogLevel::Off)?
+        .with_lora_adapters(&args.lora, args.lora_scale)?;
 
     let threads = resolve_threads(args.threads);
+    let context_size =
+        resolve_context_size(args.context_size, &llm_setup, args.allow_context_overflow)?;
+
+    if let Some(Command::Embed { text, format }) = &args.command {
+        let mut context = llm_setup.create_embedding_context(context_size, threads)?;
+        return embed::run(&llm_setup, &mut context, text.as_deref(), *format);
+    }
+
+    if let Some(Command::Bench {
+        prompt_tokens,
+        gen_tokens,
+        iterations,
+    }) = &args.command
+    {
+        let mut context = llm_setup.create_context(context_size, threads)?;
+        return bench::run(
+            &llm_setup,
+            &mut context,
+            *prompt_tokens as usize,
+            *gen_tokens as usize,
+            *iterations,
+            !args.no_warmup,
+        );
+    }
+
+    if let Some(Command::Tokenize { text }) = &args.command {
+        return match text {
+            Some(text) => tokenize::run(&llm_setup, text, false, context_size),
+            None => {
+                let full_prompt = full_prompt_text(&args, raw_prompt.as_deref())?;
+                let add_bos = !args.no_add_bos && (raw_prompt.is_none() || llm_setup.wants_bos());
+                tokenize::run(&llm_setup, &full_prompt, add_bos, context_size)
+            }
+        };
+    }
 
     let sampling = SamplingConfig {
         temperature: sanitize_temperature(args.temperature),
         top_p: clamp_top_p(args.top_p),
         top_k: args.top_k,
+        top_n_sigma: sanitize_top_n_sigma(args.top_n_sigma),
+        min_keep: args.min_keep as usize,
         repeat_penalty: sanitize_penalty(args.repeat_penalty),
-        repeat_last_n: args.repeat_last_n,
-        presence_penalty: args.presence_penalty,
-        frequency_penalty: args.frequency_penalty,
+        penalty_window: resolve_penalty_window(args.penalty_window, args.repeat_last_n),
+        presence_penalty: sanitize_additive_penalty("presence", args.presence_penalty),
+        frequency_penalty: sanitize_additive_penalty("frequency", args.frequency_penalty),
         seed: args.seed,
-        mirostat: args.mirostat,
+        sampler: resolve_sampler(args.sampler, args.mirostat)?,
         mirostat_tau: args.mirostat_tau,
         mirostat_eta: args.mirostat_eta,
+        mirostat_version: args.mirostat_version,
+        mirostat_m: args.mirostat_m,
+        bias_preset: args.bias_preset,
+        banned_tokens: args.banned_tokens.clone(),
+        ignore_eos: args.ignore_eos,
     };
 
-    let run_cfg = GenerationConfig {
-        context_size: args.context_size,
+    let mut run_cfg = GenerationConfig {
+        context_size,
         max_tokens: args.max_tokens,
+        stop_on_sentence: args.stop_on_sentence,
+        cooldown_tokens: args.cooldown_tokens,
+        max_seconds: args.max_seconds,
+        min_headroom: args.min_headroom,
         anchor_interval: if args.disable_anchors || args.anchor_interval == 0 {
             None
         } else {
             Some(args.anchor_interval)
         },
+        anchor_mode: args.anchor_mode,
+        anchor_markers: args
+            .mark_anchors
+            .then(|| (args.anchor_open.clone(), args.anchor_close.clone())),
         loop_guard: !args.disable_loop_guard,
+        warmup_tokens: args.warmup_tokens,
+        loop_action: args.loop_action,
+        loop_strikes_max: args.loop_strikes_max,
+        resume_on_loop: args.resume_on_loop,
+        on_eos: args.on_eos,
         quiet: args.quiet,
+        hide_prompt: args.hide_prompt,
+        debug_tokens: args.debug_tokens,
+        show_special: args.show_special,
+        gauge_interval: (args.show_gauge && !args.quiet && io::stderr().is_terminal())
+            .then_some(args.gauge_interval.max(1)),
+        add_bos: !args.no_add_bos,
         user_prompt: args.user_prompt.clone(),
+        prime_text: args
+            .prime_with
+            .as_deref()
+            .map(prompt::load_prime_text)
+            .transpose()?,
+        heal_tokens: args.heal_tokens,
+        raw_prompt: raw_prompt.clone(),
+        truncate_prompt: args.truncate_prompt,
+        n_keep: args.n_keep,
+        metrics: args
+            .metrics
+            .as_deref()
+            .map(|addr| -> Result<_> {
+                let metrics = Arc::new(metrics::Metrics::new());
+                metrics::serve(addr, Arc::clone(&metrics))?;
+                Ok(metrics)
+            })
+            .transpose()?,
     };
 
-    let mut output = OutputTarget::autodetect(args.output_file.as_ref())?;
+    if args.interactive
+        && args.prompt.is_none()
+        && args.prompt_file.iter().any(|p| p == Path::new("-"))
+    {
+        anyhow::bail!("--interactive reads messages from stdin, so --prompt-file can't be \"-\"");
+    }
+
+    // Raw prompts replace the template, so there's no system prompt to load
+    let system_prompt = match raw_prompt {
+        Some(_) => String::new(),
+        None => prompt::load_system_prompt(args.prompt.as_deref(), &args.prompt_file)?,
+    };
+
+    // --seed-from-prompt: same templated prompt, same seed
+    let base_seed = if args.seed_from_prompt {
+        let prompt_text = match &run_cfg.raw_prompt {
+            Some(raw) => raw.clone(),
+            None => generator::render_prompt(&system_prompt, args.user_prompt.as_deref()),
+        };
+        let seed = generator::seed_from_text(&prompt_text);
+        info!("Seed derived from prompt: {}", seed);
+        Some(seed)
+    } else {
+        args.seed
+    };
+    let seeds = run_seeds(&args, base_seed);
+    let stop = install_interrupt_handler();
+
+    // --auto-grow-context: size the window to the prompt before creating it
+    if args.auto_grow_context {
+        let needed = generator::preamble_len(&llm_setup, &system_prompt, &run_cfg)?
+            + args.min_headroom.unwrap_or(AUTO_GROW_MIN_HEADROOM);
+        if needed > run_cfg.context_size {
+            let native = llm_setup.n_ctx_train();
+            let ceiling = (native > 0 && !args.allow_context_overflow).then_some(native);
+            let grown = grown_context_size(needed, ceiling).with_context(|| {
+                format!(
+                    "Prompt needs {} tokens with headroom, beyond the model's training context of {}",
+                    needed, native
+                )
+            })?;
+            warn!(
+                "Prompt needs {} tokens with headroom; growing context from {} to {}",
+                needed, run_cfg.context_size, grown
+            );
+            run_cfg.context_size = grown;
+        }
+    }
+    let context_size = run_cfg.context_size;
 
     // Create context
-    let mut context = llm_setup.create_context(args.context_size, threads)?;
-
-    // Start infinite generation
-    generator::generate_infinite(
-        &llm_setup,
-        &mut context,
-        &args.prompt_file,
-        &run_cfg,
-        sampling,
-        &mut output,
-    )?;
+    let mut context = match args.parallel {
+        Some(streams) => {
+            llm_setup.create_parallel_context(context_size, threads, streams as usize)?
+        }
+        None => llm_setup.create_context(context_size, threads)?,
+    };
+    if !args.no_warmup && !args.check {
+        llm_setup.warm_up(&mut context)?;
+    }
+
+    // The conversation starts from the bare system prompt, not the monologue template
+    if args.interactive && !args.check {
+        let mut generator = repl::start(&llm_setup, &mut context, &system_prompt, &run_cfg)?;
+        let mut output = build_output(&args)?;
+        return repl::run(
+            &llm_setup,
+            &mut generator,
+            &run_cfg,
+            &SamplingConfig {
+                seed: base_seed,
+                ..sampling
+            },
+            &mut output,
+            &args.stop_at,
+            &stop,
+        );
+    }
+    let mut generator =
+        generator::prepare_generator(&llm_setup, &mut context, &system_prompt, &run_cfg)?;
+
+    // Everything up to sampling works; report and stop before generating
+    if args.check {
+        generator::build_sampler(
+            &llm_setup,
+            &sampling,
+            context_size,
+            generator::resolve_seed(args.seed),
+        )?;
+        let ok = if model::use_color() && io::stdout().is_terminal() {
+            "\x1b[32mOK\x1b[0m"
+        } else {
+            "OK"
+        };
+        println!(
+            "{}: {} loaded, prompt {} tokens of {} context, {} threads, sampler chain built",
+            ok,
+            model_path.display(),
+            generator.n_past(),
+            context_size,
+            threads
+        );
+        return Ok(());
+    }
+
+    if let Some(streams) = args.parallel {
+        let outputs = (0..streams)
+            .map(|_| build_output(&args))
+            .collect::<Result<Vec<_>>>()?;
+        let sampling = SamplingConfig {
+            seed: base_seed,
+            ..sampling
+        };
+        return parallel::run(
+            &llm_setup,
+            &mut generator,
+            &run_cfg,
+            &sampling,
+            outputs,
+            &stop,
+        );
+    }
+
+    if args.self_converse.is_some() {
+        drop(generator);
+        let sampling = SamplingConfig {
+            seed: base_seed,
+            ..sampling
+        };
+        return self_converse(
+            &args,
+            &llm_setup,
+            &mut context,
+            &system_prompt,
+            &run_cfg,
+            &sampling,
+            &stop,
+        );
+    }
+
+    // Runs share the loaded model and context; each starts from a reset KV cache
+    for (run, seed) in seeds.iter().enumerate() {
+        if run > 0 {
+            generator
+                .reset()
+                .context("Failed to reset context between runs")?;
+        }
+        if seeds.len() > 1 {
+            info!(
+                "=== Run {}/{} (seed {}) ===",
+                run + 1,
+                seeds.len(),
+                seed.map_or_else(|| "random".to_string(), |s| s.to_string())
+            );
+        }
+
+        let mut output = build_output(&args)?;
+        let mut triggers = triggers::KeywordTriggers::new(&args.stop_at, &args.anchor_on);
+        let reason = generator::generate_infinite(
+            &llm_setup,
+            &mut generator,
+            &run_cfg,
+            SamplingConfig {
+                seed: *seed,
+                ..sampling.clone()
+            },
+            &mut output,
+            &stop,
+            &mut |text| triggers.decide(text),
+        )?;
+        if reason == StopReason::Interrupted {
+            break;
+        }
+    }
 
     Ok(())
 }
 
+/// `--self-converse`: each turn's output becomes the next turn's user intent,
+/// re-templated onto a cleared context. Seeds count up from `sampling.seed`.
+fn self_converse(
+    args: &Args,
+    llm_setup: &llm::LLMSetup,
+    context: &mut LlamaContext,
+    system_prompt: &str,
+    run_cfg: &GenerationConfig,
+    sampling: &SamplingConfig,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let turns = args.self_converse.unwrap_or(1);
+    let mut user_prompt = args.user_prompt.clone();
+
+    for turn in 0..turns {
+        info!("=== Turn {}/{} ===", turn + 1, turns);
+        context.clear_kv_cache();
+        let turn_cfg = GenerationConfig {
+            user_prompt: user_prompt.clone(),
+            ..run_cfg.clone()
+        };
+        let mut generator =
+            generator::prepare_generator(llm_setup, context, system_prompt, &turn_cfg)?;
+
+        let mut output = build_output(args)?;
+        let mut triggers = triggers::KeywordTriggers::new(&args.stop_at, &args.anchor_on);
+        let mut transcript = String::new();
+        let reason = generator::generate_infinite(
+            llm_setup,
+            &mut generator,
+            &turn_cfg,
+            SamplingConfig {
+                seed: sampling.seed.map(|seed| seed.wrapping_add(turn)),
+                ..sampling.clone()
+            },
+            &mut output,
+            stop,
+            &mut |text| {
+                transcript.push_str(text);
+                triggers.decide(text)
+            },
+        )?;
+        if reason == StopReason::Interrupted {
+            break;
+        }
+
+        let reply = transcript.trim();
+        if reply.is_empty() {
+            info!(
+                "Turn {} produced no text; ending the conversation.",
+                turn + 1
+            );
+            break;
+        }
+        user_prompt = Some(reply.to_string());
+    }
+
+    Ok(())
+}
+
+/// The exact text handed to the tokenizer: `--raw-prompt` verbatim, otherwise
+/// the system prompt and user intent wrapped in the chat template
+fn full_prompt_text(args: &Args, raw_prompt: Option<&str>) -> Result<String> {
+    if let Some(raw) = raw_prompt {
+        return Ok(raw.to_string());
+    }
+    let system_prompt = prompt::load_system_prompt(args.prompt.as_deref(), &args.prompt_file)?;
+    Ok(generator::render_prompt(
+        &system_prompt,
+        args.user_prompt.as_deref(),
+    ))
+}
+
+/// Seeds for each run: `--seeds` as given, `--runs N` counting up from `base`
+/// (`--seed` or the prompt-derived seed; fresh random seeds without one),
+/// otherwise the single `base`
+fn run_seeds(args: &Args, base: Option<u32>) -> Vec<Option<u32>> {
+    if !args.seeds.is_empty() {
+        return args.seeds.iter().copied().map(Some).collect();
+    }
+    match args.runs {
+        Some(runs) if runs > 1 => (0..runs)
+            .map(|i| {
+                Some(match base {
+                    Some(base) => base.wrapping_add(i),
+                    None => generator::resolve_seed(None),
+                })
+            })
+            .collect(),
+        _ => vec![base],
+    }
+}
+
+/// Assembles the output target (terminal plus any file, syslog, or FIFO sinks)
+fn build_output(args: &Args) -> Result<OutputTarget> {
+    check_stdout_file_options(args)?;
+    ensure_distinct_paths(&[
+        ("--output-file", args.output_file.as_deref()),
+        ("--jsonl-file", args.jsonl_file.as_deref()),
+        ("--raw-bytes-output", args.raw_bytes_output.as_deref()),
+    ])?;
+    if args
+        .think_tag
+        .as_ref()
+        .is_some_and(|tags| tags.iter().any(String::is_empty))
+    {
+        anyhow::bail!("--think-tag delimiters must not be empty");
+    }
+    // JSON on stdout replaces the plain echo; both on one fd would interleave
+    let jsonl_to_stdout = args
+        .jsonl_file
+        .as_ref()
+        .is_some_and(|path| path.as_os_str() == "-");
+    if jsonl_to_stdout
+        && args
+            .output_file
+            .as_ref()
+            .is_some_and(|path| path.as_os_str() == "-")
+    {
+        anyhow::bail!("--output-file - and --jsonl-file - both claim stdout; pick one");
+    }
+    let file_options = FileOptions {
+        flush_interval: args.flush_interval,
+        rotate_bytes: args.rotate_bytes,
+        trim_incomplete: args.trim_incomplete_trailing,
+    };
+    Ok(OutputTarget::autodetect(
+        args.parallel.is_none() && !jsonl_to_stdout,
+        args.output_file.as_ref(),
+        args.output_dir.as_deref(),
+        file_options,
+        args.syslog,
+    )?
+    .with_sink(
+        args.fifo
+            .as_deref()
+            .map(|path| FifoOutput::open(path, args.fifo_continue))
+            .transpose()?,
+    )
+    .with_sink(
+        args.jsonl_file
+            .as_deref()
+            .map(JsonlOutput::new)
+            .transpose()?,
+    )
+    .with_sink(
+        args.raw_bytes_output
+            .as_deref()
+            .map(RawBytesOutput::new)
+            .transpose()?,
+    )
+    .with_line_buffering(args.line_buffered)
+    .with_clause_chunking(args.tts_chunks)
+    .with_blank_line_collapsing(args.collapse_blank_lines)
+    .with_think_tags(
+        args.think_tag
+            .as_deref()
+            .map(|tags| (tags[0].clone(), tags[1].clone())),
+        args.hide_think,
+    )
+    .with_token_delay(Duration::from_millis(args.delay_ms))
+    .with_background_writer(!args.sync_output))
+}
+
+/// `--output-file -` is the terminal stream itself, which the file-only
+/// options can't shape; reject them rather than silently ignoring them
+fn check_stdout_file_options(args: &Args) -> Result<(), error::Error> {
+    if !args
+        .output_file
+        .as_ref()
+        .is_some_and(|path| path.as_os_str() == "-")
+    {
+        return Ok(());
+    }
+    let ignored: Vec<&str> = [
+        (
+            "--flush-interval",
+            args.flush_interval != cli::DEFAULT_FLUSH_INTERVAL,
+        ),
+        ("--rotate-bytes", args.rotate_bytes.is_some()),
+        ("--trim-incomplete-trailing", args.trim_incomplete_trailing),
+    ]
+    .into_iter()
+    .filter_map(|(flag, given)| given.then_some(flag))
+    .collect();
+    if ignored.is_empty() {
+        return Ok(());
+    }
+    Err(error::Error::InvalidConfig(format!(
+        "{} only apply to a real output file; --output-file - is the terminal stream",
+        ignored.join(", ")
+    )))
+}
+
+/// Fails if two file sinks would write the same path and clobber each other
+fn ensure_distinct_paths(paths: &[(&str, Option<&Path>)]) -> Result<()> {
+    let given: Vec<_> = paths
+        .iter()
+        .filter_map(|(flag, path)| path.map(|p| (*flag, p)))
+        .filter(|(_, path)| path.as_os_str() != "-")
+        .collect();
+    for (i, (flag, path)) in given.iter().enumerate() {
+        for (other_flag, other) in &given[i + 1..] {
+            if std::path::absolute(path)? == std::path::absolute(other)? {
+                anyhow::bail!(
+                    "{} and {} both point at {}; give each its own file",
+                    flag,
+                    other_flag,
+                    path.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Routes diagnostics through `tracing` to stderr. `--log-level` sets our own
+/// verbosity (`--quiet` caps it at warnings); llama.cpp's chatty backend logs
+/// only get through at warn and above unless the level is `debug`.
+fn init_logging(level: LogLevel, quiet: bool) {
+    let app_level = match level {
+        LogLevel::Off => LevelFilter::OFF,
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Debug => LevelFilter::DEBUG,
+    };
+    let app_level = if quiet {
+        app_level.min(LevelFilter::WARN)
+    } else {
+        app_level
+    };
+    let backend_level = if level == LogLevel::Debug {
+        LevelFilter::DEBUG
+    } else {
+        app_level.min(LevelFilter::WARN)
+    };
+
+    let filter = Targets::new()
+        .with_default(backend_level)
+        .with_target(env!("CARGO_CRATE_NAME"), app_level);
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(io::stderr)
+                .without_time()
+                .with_target(false),
+        )
+        .with(filter)
+        .init();
+}
+
+/// Ctrl-C sets the returned flag so generation can stop and flush; a second
+/// Ctrl-C shortly after exits immediately.
+fn install_interrupt_handler() -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+
+    tokio::spawn(async move {
+        let mut first_signal: Option<Instant> = None;
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if first_signal.is_some_and(|t| t.elapsed() < FORCE_EXIT_WINDOW) {
+                eprintln!("\nForced exit.");
+                std::process::exit(130);
+            }
+            first_signal = Some(Instant::now());
+            flag.store(true, Ordering::Relaxed);
+        }
+    });
+
+    stop
+}
+
+/// Smallest power of two holding `needed` tokens, clamped to `ceiling`; `None`
+/// when even the ceiling is too small
+fn grown_context_size(needed: usize, ceiling: Option<usize>) -> Option<usize> {
+    let size = needed.checked_next_power_of_two()?;
+    match ceiling {
+        Some(max) if needed > max => None,
+        Some(max) => Some(size.min(max)),
+        None => Some(size),
+    }
+}
+
+/// Context size the run will use, from the header rather than the loaded model
+fn estimate_context(args: &Args, info: &inspect::ModelInfo) -> usize {
+    let native = info.context_length().filter(|&n| n > 0);
+    match args.context_size {
+        Some(0) => native.unwrap_or(DEFAULT_CONTEXT_CAP),
+        Some(size) => size,
+        None => native.map_or(DEFAULT_CONTEXT_CAP, |n| n.min(DEFAULT_CONTEXT_CAP)),
+    }
+}
+
+/// Warns when the estimated footprint exceeds the memory currently available
+fn check_model_fits(info: &inspect::ModelInfo, context_size: usize) {
+    let estimate = info.estimated_ram(context_size);
+    info!(
+        "Model: {} {}, ~{} RAM at {} tokens",
+        inspect::format_mib(info.file_size),
+        info.quantization(),
+        inspect::format_mib(estimate),
+        context_size
+    );
+    let Some(available) = inspect::available_memory() else {
+        return;
+    };
+    if estimate > available {
+        warn!(
+            "MODEL LIKELY TOO BIG: needs ~{} but only {} is available; expect heavy swapping \
+             or an OOM kill. Try a smaller quantization or --context-size",
+            inspect::format_mib(estimate),
+            inspect::format_mib(available)
+        );
+    }
+}
+
+fn download_options(args: &Args) -> model::DownloadOptions<'_> {
+    model::DownloadOptions {
+        quiet: args.quiet,
+        hf_mirror: args.hf_mirror.as_deref(),
+        force: args.force_download,
+        proxy: args.proxy.as_deref(),
+        timeout: (args.download_timeout > 0).then(|| Duration::from_secs(args.download_timeout)),
+    }
+}
+
+/// `--auto-config`: reads a URL model's GGUF header with range requests and
+/// prints a context size that fits in RAM and whether the default ChatML
+/// template suits it. Returns the context size to use, or `None` to keep
+/// `--context-size` or the defaults, including when the fetch fails.
+async fn auto_config(args: &Args) -> Option<usize> {
+    let info = match model::fetch_remote_info(&args.model, download_options(args)).await {
+        Ok(info) => info,
+        Err(e) => {
+            warn!(
+                "--auto-config: couldn't read the model header ({:#}); using defaults",
+                e
+            );
+            return None;
+        }
+    };
+
+    let context = info.suggested_context(DEFAULT_CONTEXT_CAP, inspect::available_memory());
+    info!(
+        "--auto-config: {} {}, context size {} (~{} RAM)",
+        inspect::format_mib(info.file_size),
+        info.quantization(),
+        context,
+        inspect::format_mib(info.estimated_ram(context))
+    );
+    match info.uses_chatml() {
+        Some(true) => info!("--auto-config: chat template is ChatML; the default template fits"),
+        Some(false) => warn!(
+            "--auto-config: chat template isn't ChatML; consider --raw-prompt with the model's own format"
+        ),
+        None => warn!("--auto-config: no chat template (base model?); consider --raw-prompt"),
+    }
+
+    match args.context_size {
+        Some(given) => {
+            info!("--auto-config: keeping --context-size {}", given);
+            None
+        }
+        None => Some(context),
+    }
+}
+
+/// `--inspect-model`: header metadata and the RAM estimate on stdout
+fn print_model_info(info: &inspect::ModelInfo, context_size: usize) {
+    let estimate = info.estimated_ram(context_size);
+    println!("File size:     {}", inspect::format_mib(info.file_size));
+    println!("Quantization:  {}", info.quantization());
+    println!("GGUF version:  {}", info.version);
+    println!("Tensors:       {}", info.tensor_count);
+    if let Some(kv) = info.kv_cache_bytes(context_size) {
+        println!(
+            "KV cache:      {} at {} tokens",
+            inspect::format_mib(kv),
+            context_size
+        );
+    }
+    println!("Estimated RAM: {}", inspect::format_mib(estimate));
+    match inspect::available_memory() {
+        Some(available) => println!(
+            "Available RAM: {}{}",
+            inspect::format_mib(available),
+            if estimate > available {
+                " (TOO SMALL)"
+            } else {
+                ""
+            }
+        ),
+        None => println!("Available RAM: unknown"),
+    }
+    println!();
+    for (key, value) in &info.metadata {
+        println!("{} = {}", key, value);
+    }
+}
+
+/// Uses `--context-size` when given (0 for the model's full training context),
+/// otherwise the training context capped at `DEFAULT_CONTEXT_CAP`
+fn resolve_context_size(
+    requested: Option<usize>,
+    llm_setup: &llm::LLMSetup,
+    allow_overflow: bool,
+) -> Result<usize> {
+    let native = llm_setup.n_ctx_train();
+
+    if requested == Some(0) {
+        if native == 0 {
+            anyhow::bail!(
+                "--context-size 0 asks for the model's training context, but the model doesn't \
+                 report one; pass an explicit size"
+            );
+        }
+        info!(
+            "Context size: {} tokens (model native, from --context-size 0)",
+            native
+        );
+        return Ok(native);
+    }
+
+    if let Some(size) = requested {
+        // Positions past the trained length are extrapolated; without rope scaling
+        // the model degrades into garbage rather than failing outright
+        if native > 0 && size > native {
+            if !allow_overflow {
+                anyhow::bail!(
+                    "--context-size {} exceeds the model's training context of {} tokens; \
+                     output past {} tokens is usually incoherent. Use --context-size {} or \
+                     pass --allow-context-overflow to run anyway",
+                    size,
+                    native,
+                    native,
+                    native
+                );
+            }
+            warn!(
+                "Context size {} exceeds the model's training context ({}); expect degraded output",
+                size, native
+            );
+        }
+        return Ok(size);
+    }
+
+    let size = if native == 0 {
+        DEFAULT_CONTEXT_CAP
+    } else {
+        native.min(DEFAULT_CONTEXT_CAP)
+    };
+    info!(
+        "Context size: {} tokens (model native {}, capped at {}; override with --context-size)",
+        size, native, DEFAULT_CONTEXT_CAP
+    );
+    Ok(size)
+}
+
+/// `--penalty-window`, or the deprecated `--repeat-last-n` mapped onto it
+fn resolve_penalty_window(
+    window: Option<PenaltyWindow>,
+    repeat_last_n: Option<i32>,
+) -> PenaltyWindow {
+    match (window, repeat_last_n) {
+        (Some(window), _) => window,
+        (None, Some(n)) => {
+            let window = PenaltyWindow::from_repeat_last_n(n);
+            warn!(
+                "--repeat-last-n is deprecated; use --penalty-window {}",
+                match window {
+                    PenaltyWindow::Tokens(n) => n.to_string(),
+                    _ => "full".to_string(),
+                }
+            );
+            window
+        }
+        (None, None) => PenaltyWindow::Full,
+    }
+}
+
+/// `--sampler` wins; the legacy `--mirostat` flag only conflicts with other choices
+fn resolve_sampler(requested: Option<SamplerKind>, mirostat: bool) -> Result<SamplerKind> {
+    match (requested, mirostat) {
+        (None, false) => Ok(SamplerKind::Dist),
+        (None, true) | (Some(SamplerKind::Mirostat), _) => Ok(SamplerKind::Mirostat),
+        (Some(kind), true) => anyhow::bail!(
+            "--mirostat conflicts with --sampler {}; pick one terminal sampler",
+            format!("{:?}", kind).to_lowercase()
+        ),
+        (Some(kind), false) => Ok(kind),
+    }
+}
+
 fn resolve_threads(requested: Option<usize>) -> usize {
     requested.unwrap_or_else(|| {
         thread::available_parallelism()
@@ -79,6 +894,25 @@ fn resolve_threads(requested: Option<usize>) -> usize {
     })
 }
 
+/// `--list-presets` / `--list-templates`, read from the same tables selection uses
+fn print_listings(args: &Args) {
+    if args.list_presets {
+        println!("Bias presets (--bias-preset):");
+        for preset in BiasPreset::value_variants() {
+            if let Some(value) = preset.to_possible_value() {
+                let help = value.get_help().map(|h| h.to_string()).unwrap_or_default();
+                println!("  {:<10} {}", value.get_name(), help);
+            }
+        }
+    }
+    if args.list_templates {
+        println!("Prompt templates:");
+        for (name, description) in generator::PROMPT_TEMPLATES {
+            println!("  {:<10} {}", name, description);
+        }
+    }
+}
+
 fn sanitize_temperature(temp: f32) -> f32 {
     temp.max(0.0)
 }
@@ -90,3 +924,94 @@ fn clamp_top_p(top_p: f32) -> f32 {
 fn sanitize_penalty(penalty: f32) -> f32 {
     penalty.max(0.0)
 }
+
+/// Range llama.cpp's presence/frequency penalties behave within; beyond it the
+/// distribution collapses into empty or garbage output
+const ADDITIVE_PENALTY_RANGE: (f32, f32) = (-2.0, 2.0);
+
+fn clamp_additive_penalty(penalty: f32) -> f32 {
+    let (min, max) = ADDITIVE_PENALTY_RANGE;
+    if penalty.is_nan() {
+        return 0.0;
+    }
+    penalty.clamp(min, max)
+}
+
+fn sanitize_additive_penalty(name: &str, penalty: f32) -> f32 {
+    let clamped = clamp_additive_penalty(penalty);
+    if clamped != penalty {
+        warn!(
+            "--{}-penalty {} is outside [{}, {}]; using {}",
+            name, penalty, ADDITIVE_PENALTY_RANGE.0, ADDITIVE_PENALTY_RANGE.1, clamped
+        );
+    }
+    clamped
+}
+
+/// Negative (or NaN) sigma counts would keep nothing; drop the stage instead
+fn sanitize_top_n_sigma(n: Option<f32>) -> Option<f32> {
+    let n = n?;
+    if n >= 0.0 {
+        return Some(n);
+    }
+    warn!("--top-n-sigma {} must be non-negative; ignoring it", n);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_sinks_must_not_share_a_path() {
+        let story = Path::new("story.txt");
+        let jsonl = Path::new("story.jsonl");
+        assert!(
+            ensure_distinct_paths(&[("a", Some(story)), ("b", Some(jsonl)), ("c", None)]).is_ok()
+        );
+        assert!(
+            ensure_distinct_paths(&[("a", Some(story)), ("b", Some(Path::new("./story.txt")))])
+                .is_err()
+        );
+        assert!(
+            ensure_distinct_paths(&[("a", Some(Path::new("-"))), ("b", Some(Path::new("-")))])
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn stdout_output_file_rejects_file_only_options() {
+        use clap::Parser;
+        let parse = |extra: &[&str]| {
+            Args::try_parse_from(["ooc", "--output-file", "-"].iter().chain(extra)).unwrap()
+        };
+        assert!(check_stdout_file_options(&parse(&[])).is_ok());
+        assert!(check_stdout_file_options(&parse(&["--rotate-bytes", "100"])).is_err());
+        let err = check_stdout_file_options(&parse(&["--flush-interval", "1"])).unwrap_err();
+        assert!(err.to_string().contains("--flush-interval"), "{}", err);
+
+        let file =
+            Args::try_parse_from(["ooc", "--output-file", "out.txt", "--rotate-bytes", "100"])
+                .unwrap();
+        assert!(check_stdout_file_options(&file).is_ok());
+    }
+
+    #[test]
+    fn context_grows_to_power_of_two_under_ceiling() {
+        assert_eq!(grown_context_size(700, None), Some(1024));
+        assert_eq!(grown_context_size(1024, Some(8192)), Some(1024));
+        assert_eq!(grown_context_size(1500, Some(1200)), None);
+        assert_eq!(grown_context_size(1100, Some(1200)), Some(1200));
+    }
+
+    #[test]
+    fn additive_penalty_clamps_to_range() {
+        assert_eq!(clamp_additive_penalty(1.35), 1.35);
+        assert_eq!(clamp_additive_penalty(2.0), 2.0);
+        assert_eq!(clamp_additive_penalty(-2.0), -2.0);
+        assert_eq!(clamp_additive_penalty(2.01), 2.0);
+        assert_eq!(clamp_additive_penalty(-7.5), -2.0);
+        assert_eq!(clamp_additive_penalty(f32::INFINITY), 2.0);
+        assert_eq!(clamp_additive_penalty(f32::NAN), 0.0);
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::{Context, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Live counters for `--metrics`, updated from the generation loop and read by
+/// the scrape handler. Floats are stored as their bit patterns.
+#[derive(Debug)]
+pub struct Metrics {
+    started: Instant,
+    tokens_generated: AtomicU64,
+    tokens_per_second: AtomicU64,
+    context_used: AtomicU64,
+    context_size: AtomicU64,
+    anchors_injected: AtomicU64,
+    loop_strikes: AtomicU64,
+    coherence: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            tokens_generated: AtomicU64::new(0),
+            tokens_per_second: AtomicU64::new(0f64.to_bits()),
+            context_used: AtomicU64::new(0),
+            context_size: AtomicU64::new(0),
+            anchors_injected: AtomicU64::new(0),
+            loop_strikes: AtomicU64::new(0),
+            coherence: AtomicU64::new(f64::NAN.to_bits()),
+        }
+    }
+
+    pub fn add_tokens(&self, n: usize) {
+        self.tokens_generated.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Current run's fill level and throughput
+    pub fn observe(
+        &self,
+        n_past: usize,
+        context_size: usize,
+        run_tokens: usize,
+        run_time: Duration,
+    ) {
+        self.context_used.store(n_past as u64, Ordering::Relaxed);
+        self.context_size
+            .store(context_size as u64, Ordering::Relaxed);
+        let rate = run_tokens as f64 / run_time.as_secs_f64().max(f64::EPSILON);
+        self.tokens_per_second
+            .store(rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn anchor_injected(&self) {
+        self.anchors_injected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn loop_strike(&self) {
+        self.loop_strikes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Latest coherence window score
+    pub fn coherence(&self, score: f32) {
+        self.coherence
+            .store(f64::from(score).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Prometheus text exposition format (version 0.0.4)
+    fn render(&self) -> String {
+        let used = self.context_used.load(Ordering::Relaxed);
+        let size = self.context_size.load(Ordering::Relaxed);
+        let fill = if size == 0 {
+            0.0
+        } else {
+            used as f64 / size as f64
+        };
+        let rate = f64::from_bits(self.tokens_per_second.load(Ordering::Relaxed));
+
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            ));
+        };
+        metric(
+            "ooc_tokens_generated_total",
+            "counter",
+            "Tokens generated (anchors included) since start",
+            self.tokens_generated.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "ooc_tokens_per_second",
+            "gauge",
+            "Generation throughput of the current run",
+            format!("{:.3}", rate),
+        );
+        metric(
+            "ooc_context_used_tokens",
+            "gauge",
+            "KV positions occupied, prompt included",
+            used.to_string(),
+        );
+        metric(
+            "ooc_context_fill_ratio",
+            "gauge",
+            "Fraction of the context window in use",
+            format!("{:.4}", fill),
+        );
+        metric(
+            "ooc_anchors_injected_total",
+            "counter",
+            "Anti-loop anchors injected",
+            self.anchors_injected.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "ooc_loop_strikes_total",
+            "counter",
+            "Repetition detections by the loop guard",
+            self.loop_strikes.load(Ordering::Relaxed).to_string(),
+        );
+        // NaN until the first window fills, which Prometheus accepts
+        metric(
+            "ooc_coherence_score",
+            "gauge",
+            "Lexical diversity of the latest 120-token window (1 = no repeats)",
+            format!(
+                "{:.4}",
+                f64::from_bits(self.coherence.load(Ordering::Relaxed))
+            ),
+        );
+        metric(
+            "ooc_uptime_seconds",
+            "gauge",
+            "Seconds since the process started",
+            format!("{:.1}", self.started.elapsed().as_secs_f64()),
+        );
+        out
+    }
+}
+
+/// Serves `GET /metrics` on `addr` from a background thread. Binding happens
+/// up front so a bad address fails the run immediately.
+pub fn serve(addr: &str, metrics: Arc<Metrics>) -> Result<()> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Failed to bind metrics on {}", addr))?;
+    tracing::info!("Serving metrics on http://{}/metrics", addr);
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // One scraper at a time is plenty; a broken client shouldn't stop the server
+            let _ = handle(stream, &metrics);
+        }
+    });
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Drain headers up to the blank line
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", "Not found; try /metrics\n".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
@@ -3,16 +3,51 @@ use futures_util::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::cmp::min;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, IsTerminal, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, instrument};
+
+use crate::error::Error;
+use crate::inspect::{self, ModelInfo};
+
+const HF_BASE_URL: &str = "https://huggingface.co";
+
+/// First range fetched by `fetch_remote_info`, doubled until the header fits
+const HEADER_PROBE_BYTES: u64 = 4 << 20;
+
+/// Give up on headers larger than this; no real vocabulary needs more
+const HEADER_PROBE_MAX_BYTES: u64 = 64 << 20;
+
+/// How `resolve_model` fetches remote models
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DownloadOptions<'a> {
+    /// Hide the download progress bar; status still goes through `tracing`
+    pub quiet: bool,
+    /// Base URL replacing `https://huggingface.co` (the `HF_ENDPOINT` convention)
+    pub hf_mirror: Option<&'a str>,
+    /// Replace an existing download instead of reusing it
+    pub force: bool,
+    /// Proxy for all download traffic; `HTTPS_PROXY`/`HTTP_PROXY` apply when unset
+    pub proxy: Option<&'a str>,
+    /// Connect timeout, and the longest the transfer may go without receiving
+    /// data; `None` waits forever
+    pub timeout: Option<Duration>,
+}
 
 /// Resolves the model path and ensures it exists
 ///
 /// If `model_spec` is a URL, downloads to `model_dir` and returns the local path.
 /// If `model_spec` is a local path, verifies it exists and returns it.
-pub async fn resolve_model(model_spec: &str, model_dir: &Path) -> Result<PathBuf> {
+pub async fn resolve_model(
+    model_spec: &str,
+    model_dir: &Path,
+    options: DownloadOptions<'_>,
+) -> Result<PathBuf, Error> {
     // Check if model_spec is a URL
-    if model_spec.starts_with("http://") || model_spec.starts_with("https://") {
+    if let Some(url) = remote_url(model_spec, options) {
+        let model_spec = url.as_str();
+
         // Extract filename from URL
         let filename = model_spec
             .rsplit('/')
@@ -23,19 +58,28 @@ pub async fn resolve_model(model_spec: &str, model_dir: &Path) -> Result<PathBuf
 
         // Check if already downloaded
         if model_path.exists() {
-            println!("Model found at: {}", model_path.display());
-            return Ok(model_path);
+            if !options.force {
+                info!("Model found at: {}", model_path.display());
+                return Ok(model_path);
+            }
+            // The finished download replaces it; a failed one leaves it in place
+            info!(
+                "Re-downloading cached model for --force-download: {}",
+                model_path.display()
+            );
+        } else {
+            info!("Model not found locally");
         }
 
-        println!("Model not found locally");
-        println!("Downloading from: {}", model_spec);
+        info!("Downloading from: {}", model_spec);
 
         // Create model directory if it doesn't exist
         std::fs::create_dir_all(model_dir)
             .with_context(|| format!("Failed to create directory: {}", model_dir.display()))?;
 
         // Download the model
-        download_model(model_spec, &model_path).await?;
+        let client = http_client(options.proxy, options.timeout)?;
+        download_model(&client, model_spec, &model_path, options).await?;
 
         Ok(model_path)
     } else {
@@ -43,69 +87,291 @@ pub async fn resolve_model(model_spec: &str, model_dir: &Path) -> Result<PathBuf
         let model_path = PathBuf::from(model_spec);
 
         if !model_path.exists() {
-            anyhow::bail!("Model file not found: {}", model_path.display());
+            return Err(Error::ModelNotFound(model_path));
         }
 
-        println!("Using local model: {}", model_path.display());
+        info!("Using local model: {}", model_path.display());
         Ok(model_path)
     }
 }
 
+/// The URL to fetch for `model_spec`, honoring `--hf-mirror`; `None` for local paths
+fn remote_url(model_spec: &str, options: DownloadOptions<'_>) -> Option<String> {
+    if !(model_spec.starts_with("http://") || model_spec.starts_with("https://")) {
+        return None;
+    }
+    Some(match options.hf_mirror {
+        Some(mirror) => apply_hf_mirror(model_spec, mirror),
+        None => model_spec.to_string(),
+    })
+}
+
+/// Reads a URL model's GGUF header with HTTP range requests, without
+/// downloading the weights. Fails for local paths and for servers that
+/// ignore ranges.
+pub async fn fetch_remote_info(
+    model_spec: &str,
+    options: DownloadOptions<'_>,
+) -> Result<ModelInfo> {
+    let url = remote_url(model_spec, options).context("not a URL model")?;
+    let client = http_client(options.proxy, options.timeout)?;
+
+    let mut len = HEADER_PROBE_BYTES;
+    loop {
+        let response = client
+            .get(&url)
+            .header(reqwest::header::RANGE, format!("bytes=0-{}", len - 1))
+            .send()
+            .await?
+            .error_for_status()?;
+        // A 200 would stream the whole model
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            anyhow::bail!("server doesn't support range requests");
+        }
+        let total = response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|v| v.parse::<u64>().ok())
+            .context("missing Content-Range size")?;
+        let bytes = response.bytes().await?;
+
+        if let Some(info) = ModelInfo::from_prefix(&bytes, total)? {
+            return Ok(info);
+        }
+        if bytes.len() as u64 >= total || len >= HEADER_PROBE_MAX_BYTES {
+            anyhow::bail!(
+                "GGUF header doesn't fit in the first {}",
+                inspect::format_mib(len)
+            );
+        }
+        len *= 2;
+    }
+}
+
+/// Points a Hugging Face URL at `mirror`; other hosts pass through untouched
+fn apply_hf_mirror(url: &str, mirror: &str) -> String {
+    match url.strip_prefix(HF_BASE_URL) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{}", mirror.trim_end_matches('/'), rest)
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Builds the download client. An explicit `--proxy` carries all traffic;
+/// otherwise `HTTPS_PROXY`/`HTTP_PROXY` (or their lowercase forms) are applied
+/// per scheme, with `NO_PROXY` exclusions.
+fn http_client(proxy: Option<&str>, timeout: Option<Duration>) -> Result<reqwest::Client, Error> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(timeout) = timeout {
+        builder = builder.connect_timeout(timeout).read_timeout(timeout);
+    }
+    match proxy {
+        Some(url) => {
+            let url = parse_proxy_url(url).map_err(|reason| {
+                Error::InvalidConfig(format!("Invalid --proxy URL {:?}: {}", url, reason))
+            })?;
+            builder = builder.proxy(reqwest::Proxy::all(url).context("Failed to configure proxy")?);
+        }
+        None => {
+            for (var, https) in [("HTTPS_PROXY", true), ("HTTP_PROXY", false)] {
+                let Some(url) = proxy_from_env(var) else {
+                    continue;
+                };
+                let url = parse_proxy_url(&url).map_err(|reason| {
+                    Error::InvalidConfig(format!("Invalid {} URL {:?}: {}", var, url, reason))
+                })?;
+                let proxy = if https {
+                    reqwest::Proxy::https(url)
+                } else {
+                    reqwest::Proxy::http(url)
+                }
+                .context("Failed to configure proxy")?;
+                builder = builder.proxy(proxy.no_proxy(reqwest::NoProxy::from_env()));
+            }
+        }
+    }
+    Ok(builder.build().context("Failed to create HTTP client")?)
+}
+
+/// `var` or its lowercase spelling, ignoring empty values
+fn proxy_from_env(var: &str) -> Option<String> {
+    [var.to_string(), var.to_lowercase()]
+        .into_iter()
+        .find_map(|name| std::env::var(name).ok().filter(|v| !v.is_empty()))
+}
+
+/// Proxies must be absolute http(s) URLs with a host, e.g. `http://proxy.corp:3128`
+fn parse_proxy_url(url: &str) -> Result<reqwest::Url, String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("unsupported scheme {:?}", parsed.scheme()));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err("missing host".to_string());
+    }
+    Ok(parsed)
+}
+
 /// Downloads a model from a URL with progress bar
-async fn download_model(url: &str, destination: &Path) -> Result<()> {
-    // Create HTTP client
-    let client = reqwest::Client::new();
+#[instrument(name = "download", skip_all, fields(%url))]
+async fn download_model(
+    client: &reqwest::Client,
+    url: &str,
+    destination: &Path,
+    options: DownloadOptions<'_>,
+) -> Result<(), Error> {
+    let quiet = options.quiet;
+    let failed = |reason: String| Error::DownloadFailed {
+        url: url.to_string(),
+        reason,
+    };
+    // Stalls are worth calling out: the fix is a flag, not a retry
+    let describe = |what: &str, e: reqwest::Error| match options.timeout {
+        Some(timeout) if e.is_timeout() => format!(
+            "timed out after {}s without data (raise --download-timeout)",
+            timeout.as_secs()
+        ),
+        _ => format!("{}: {}", what, e),
+    };
 
     // Send GET request
     let response = client
         .get(url)
         .send()
         .await
-        .context("Failed to send download request")?;
+        .map_err(|e| failed(describe("request failed", e)))?;
 
     // Check if request was successful
     if !response.status().is_success() {
-        anyhow::bail!("Failed to download model: HTTP {}", response.status());
+        return Err(failed(format!("HTTP {}", response.status())));
     }
 
     // Get content length for progress bar
     let total_size = response.content_length().unwrap_or(0);
 
-    // Create progress bar
-    let pb = ProgressBar::new(total_size);
+    let name = destination.file_name().unwrap().to_string_lossy();
+
+    // Animated bar on a terminal, progress events when stderr is redirected,
+    // and nothing at all under --quiet
+    let interactive = io::stderr().is_terminal();
+    let report_lines = !quiet && !interactive;
+    let pb = if !quiet && interactive {
+        ProgressBar::new(total_size)
+    } else {
+        ProgressBar::hidden()
+    };
+    let template = if use_color() {
+        "{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})"
+    } else {
+        "{msg}\n{spinner} [{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({eta})"
+    };
     pb.set_style(
         ProgressStyle::default_bar()
-            .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .template(template)
             .unwrap()
             .progress_chars("#>-"),
     );
-    pb.set_message(format!(
-        "Downloading {}",
-        destination.file_name().unwrap().to_string_lossy()
-    ));
+    pb.set_message(format!("Downloading {}", name));
+    if report_lines {
+        info!("Downloading {} ({} bytes)", name, total_size);
+    }
 
-    // Create output file
-    let mut file = File::create(destination)
-        .with_context(|| format!("Failed to create file: {}", destination.display()))?;
+    // Stream into a sibling .part file: a stalled or dropped download must not
+    // leave a truncated GGUF where the cache lookup would take it for the model
+    let partial = partial_path(destination);
+    let mut file = File::create(&partial)
+        .with_context(|| format!("Failed to create file: {}", partial.display()))?;
 
     // Stream download with progress
     let mut downloaded: u64 = 0;
+    let mut next_report = 10u64;
     let mut stream = response.bytes_stream();
 
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk.context("Failed to read chunk")?;
-        file.write_all(&chunk).context("Failed to write to file")?;
+    let streamed = async {
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| failed(describe("connection dropped", e)))?;
+            file.write_all(&chunk).context("Failed to write to file")?;
+
+            let new = min(downloaded + (chunk.len() as u64), total_size);
+            downloaded = new;
+            pb.set_position(new);
 
-        let new = min(downloaded + (chunk.len() as u64), total_size);
-        downloaded = new;
-        pb.set_position(new);
+            if report_lines && total_size > 0 {
+                let percent = downloaded * 100 / total_size;
+                if percent >= next_report {
+                    info!(
+                        "Downloaded {}% ({}/{} bytes)",
+                        percent, downloaded, total_size
+                    );
+                    next_report = (percent / 10 + 1) * 10;
+                }
+            }
+        }
+        file.flush().context("Failed to write to file")?;
+        Ok::<(), Error>(())
+    }
+    .await;
+    if let Err(e) = streamed {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
     }
+    drop(file);
+    std::fs::rename(&partial, destination)
+        .with_context(|| format!("Failed to move download to {}", destination.display()))?;
 
-    pb.finish_with_message(format!(
-        "Downloaded {}",
-        destination.file_name().unwrap().to_string_lossy()
-    ));
-    println!("Model downloaded successfully!");
+    pb.finish_with_message(format!("Downloaded {}", name));
+    info!("Model downloaded successfully!");
 
     Ok(())
 }
+
+/// `model.gguf` -> `model.gguf.part`, where a download lands until it completes
+fn partial_path(destination: &Path) -> PathBuf {
+    let mut name = destination.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Honors the `NO_COLOR` convention (set and non-empty disables color)
+pub fn use_color() -> bool {
+    std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hf_mirror_rewrites_only_hugging_face_urls() {
+        assert_eq!(
+            apply_hf_mirror(
+                "https://huggingface.co/org/repo/resolve/main/m.gguf",
+                "https://hf-mirror.example/"
+            ),
+            "https://hf-mirror.example/org/repo/resolve/main/m.gguf"
+        );
+        assert_eq!(
+            apply_hf_mirror("https://example.com/m.gguf", "https://hf-mirror.example"),
+            "https://example.com/m.gguf"
+        );
+        assert_eq!(
+            apply_hf_mirror(
+                "https://huggingface.com/m.gguf",
+                "https://hf-mirror.example"
+            ),
+            "https://huggingface.com/m.gguf"
+        );
+    }
+
+    #[test]
+    fn proxy_urls_are_validated() {
+        assert!(parse_proxy_url("http://proxy.corp:3128").is_ok());
+        assert!(parse_proxy_url("https://user:pw@proxy.corp").is_ok());
+        assert!(parse_proxy_url("proxy.corp:3128").is_err());
+        assert!(parse_proxy_url("ftp://proxy.corp").is_err());
+        assert!(parse_proxy_url("not a url").is_err());
+    }
+}
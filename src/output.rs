@@ -1,43 +1,543 @@
-use anyhow::Result;
+use crate::generator::StopReason;
+use crate::loop_guard::Coherence;
+use anyhow::{Context, Result, anyhow};
+use std::ffi::CString;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+use tracing::{debug, info};
+
+/// A destination for generated text. `OutputTarget` fans every token out to
+/// its sinks in order, so a new backend only has to implement this.
+pub trait OutputSink: Send {
+    fn write_token(&mut self, text: &str) -> Result<()>;
+
+    /// End of the stream: write any closing newline or final record and flush.
+    fn finish(&mut self, reason: StopReason) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Mirror a diagnostic warning somewhere besides stderr.
+    fn warn(&mut self, _message: &str) {}
+
+    /// The detokenizer's exact bytes for a sampled token; only diagnostic sinks care.
+    fn write_raw_bytes(&mut self, _token: i32, _bytes: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// The run's coherence scores, delivered just before `finish`.
+    fn record_coherence(&mut self, _coherence: &Coherence) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returned by a sink whose reader went away when it may drop out instead of
+/// failing the run; the message is reported as a warning.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct SinkDisconnected(pub String);
 
 /// Output abstraction so we can swap terminal printing for a hardware display later.
 pub struct OutputTarget {
-    terminal: TerminalOutput,
-    file: Option<FileOutput>,
+    sinks: Vec<Box<dyn OutputSink>>,
+    /// Pending text when emitting whole lines/sentences instead of tokens
+    line_buffer: Option<String>,
+    /// Where the line buffer is cut
+    chunking: Chunking,
+    /// Typewriter pause after each token
+    token_delay: Duration,
+    /// Trailing newline count carried across tokens when collapsing blank lines
+    newline_run: Option<usize>,
+    /// `--think-tag`: reasoning spans diverted away from the sinks
+    think: Option<ThinkFilter>,
+    /// When set, every call is forwarded to a writer thread owning the real sinks
+    writer: Option<BackgroundWriter>,
 }
 
+/// Tokens queued for the writer thread before generation blocks on it
+const OUTPUT_QUEUE_DEPTH: usize = 256;
+
 impl OutputTarget {
-    /// Attempt to auto-select an output. For now we always fall back to terminal output,
-    /// but we probe for SPI devices so we can hook up the ILI9488 path later.
-    pub fn autodetect(mirror_file: Option<&PathBuf>) -> Result<Self> {
+    /// Assemble the sinks the CLI asked for: the terminal (off when several
+    /// streams share stdout, as with `--parallel`), a mirror file or an
+    /// `--output-dir` run with its stats sidecar, and syslog. We probe for SPI
+    /// devices so we can hook up the ILI9488 path later.
+    pub fn autodetect(
+        terminal: bool,
+        mirror_file: Option<&PathBuf>,
+        output_dir: Option<&Path>,
+        file_options: FileOptions,
+        syslog: bool,
+    ) -> Result<Self> {
         if has_spi_device() {
-            eprintln!(
-                "SPI device detected; ILI9488 rendering not wired yet, using terminal output."
-            );
+            info!("SPI device detected; ILI9488 rendering not wired yet, using terminal output.");
         }
 
-        let file = if let Some(path) = mirror_file {
-            Some(FileOutput::new(path)?)
-        } else {
-            None
-        };
+        let mut sinks: Vec<Box<dyn OutputSink>> = Vec::new();
+        if terminal {
+            sinks.push(Box::new(TerminalOutput::new()));
+        }
+        if mirror_file.is_some_and(|path| path.as_os_str() == "-") {
+            // "-" is stdout, which the terminal sink already writes; a second
+            // writer on the same fd would print every token twice
+        } else if let Some(path) = mirror_file {
+            sinks.push(Box::new(FileOutput::new(path, file_options)?));
+        } else if let Some(dir) = output_dir {
+            let run = RunSidecar::new(dir)?;
+            info!("Writing this run to {}", run.text_path.display());
+            sinks.push(Box::new(FileOutput::new(&run.text_path, file_options)?));
+            sinks.push(Box::new(run));
+        }
+        if syslog {
+            sinks.push(Box::new(SyslogOutput::new()?));
+        }
 
-        Ok(OutputTarget {
-            terminal: TerminalOutput::new(),
-            file,
-        })
+        Ok(Self::with_sinks(sinks))
+    }
+
+    /// A target writing only to `sinks`, with no buffering or pacing.
+    pub fn with_sinks(sinks: Vec<Box<dyn OutputSink>>) -> Self {
+        OutputTarget {
+            sinks,
+            line_buffer: None,
+            chunking: Chunking::Lines,
+            token_delay: Duration::ZERO,
+            newline_run: None,
+            think: None,
+            writer: None,
+        }
+    }
+
+    /// Additionally write to `sink`, after the existing ones.
+    pub fn with_sink(mut self, sink: Option<impl OutputSink + 'static>) -> Self {
+        if let Some(sink) = sink {
+            self.sinks.push(Box::new(sink));
+        }
+        self
+    }
+
+    /// Hold tokens back until a newline or sentence end instead of streaming each one.
+    pub fn with_line_buffering(mut self, enabled: bool) -> Self {
+        self.line_buffer = enabled.then(String::new);
+        self
+    }
+
+    /// Emit clause-sized chunks, one per line, for piping into a TTS engine.
+    /// Takes over from line buffering when enabled.
+    pub fn with_clause_chunking(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.line_buffer = Some(String::new());
+            self.chunking = Chunking::Clauses;
+        }
+        self
+    }
+
+    /// Squeeze runs of three or more newlines down to a single blank line.
+    pub fn with_blank_line_collapsing(mut self, enabled: bool) -> Self {
+        self.newline_run = enabled.then_some(0);
+        self
+    }
+
+    /// Divert text between `open` and `close` to stderr (or drop it when `hide`
+    /// is set) so only the answer reaches stdout and files.
+    pub fn with_think_tags(mut self, tags: Option<(String, String)>, hide: bool) -> Self {
+        self.think = tags.map(|(open, close)| ThinkFilter::new(open, close, hide));
+        self
+    }
+
+    /// Sleep this long after every token for steady typewriter pacing (zero disables).
+    pub fn with_token_delay(mut self, delay: Duration) -> Self {
+        self.token_delay = delay;
+        self
+    }
+
+    /// Move all writing (flushes, FIFO/syslog I/O, pacing) onto a dedicated thread
+    /// so slow sinks don't stall inference. Messages keep their order; `finish`
+    /// drains the queue and joins the thread. Apply after the other builders.
+    pub fn with_background_writer(self, enabled: bool) -> Self {
+        if !enabled {
+            return self;
+        }
+
+        let (sender, receiver) = mpsc::sync_channel::<OutputMessage>(OUTPUT_QUEUE_DEPTH);
+        let mut sinks = self;
+        let handle = thread::spawn(move || -> Result<()> {
+            for message in receiver {
+                match message {
+                    OutputMessage::Token(text) => sinks.write_token(&text)?,
+                    OutputMessage::RawBytes(token, bytes) => {
+                        sinks.write_raw_bytes(token, &bytes)?
+                    }
+                    OutputMessage::Coherence(coherence) => sinks.record_coherence(&coherence)?,
+                    OutputMessage::Warn(text) => sinks.warn(&text),
+                    OutputMessage::Flush => sinks.flush()?,
+                    OutputMessage::Finish(reason) => sinks.finish(reason)?,
+                }
+            }
+            Ok(())
+        });
+
+        OutputTarget {
+            writer: Some(BackgroundWriter {
+                sender: Some(sender),
+                handle: Some(handle),
+            }),
+            ..Self::with_sinks(Vec::new())
+        }
     }
 
     pub fn write_token(&mut self, text: &str) -> Result<()> {
-        self.terminal.write(text)?;
-        if let Some(f) = &mut self.file {
-            f.write(text)?;
+        if let Some(writer) = &mut self.writer {
+            return writer.send(OutputMessage::Token(text.to_string()));
+        }
+
+        match &mut self.think {
+            None => self.write_answer(text),
+            Some(think) => {
+                let answer = think.route(text);
+                self.write_answer(&answer)
+            }
+        }
+    }
+
+    /// Everything after `--think-tag` routing: collapsing, buffering, pacing
+    fn write_answer(&mut self, text: &str) -> Result<()> {
+        let collapsed;
+        let text = match &mut self.newline_run {
+            None => text,
+            Some(run) => {
+                collapsed = collapse_newlines(text, run);
+                collapsed.as_str()
+            }
+        };
+
+        match &mut self.line_buffer {
+            None => self.emit(text)?,
+            Some(buffer) => {
+                buffer.push_str(text);
+                let boundary = match self.chunking {
+                    Chunking::Lines => buffer.rfind(['\n', '.', '!', '?']).map(|end| end + 1),
+                    Chunking::Clauses => clause_boundary(buffer),
+                };
+                if let Some(end) = boundary {
+                    let ready: String = buffer.drain(..end).collect();
+                    self.emit_chunk(&ready)?;
+                }
+            }
+        }
+
+        if !self.token_delay.is_zero() {
+            thread::sleep(self.token_delay);
+        }
+        Ok(())
+    }
+
+    /// Record the detokenizer's bytes for `token` in the `--raw-bytes-output`
+    /// file, if any. Independent of the text sinks and their buffering.
+    pub fn write_raw_bytes(&mut self, token: i32, bytes: &[u8]) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            return writer.send(OutputMessage::RawBytes(token, bytes.to_vec()));
+        }
+        self.for_each_sink(|sink| sink.write_raw_bytes(token, bytes))
+    }
+
+    /// Hand the run's coherence scores to sinks that report them
+    pub fn record_coherence(&mut self, coherence: &Coherence) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            return writer.send(OutputMessage::Coherence(coherence.clone()));
+        }
+        self.for_each_sink(|sink| sink.record_coherence(coherence))
+    }
+
+    /// Clause chunks become single trimmed lines; line chunks pass through as-is
+    fn emit_chunk(&mut self, text: &str) -> Result<()> {
+        match self.chunking {
+            Chunking::Lines => self.emit(text),
+            Chunking::Clauses => {
+                let clause = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                if clause.is_empty() {
+                    return Ok(());
+                }
+                self.emit(&format!("{}\n", clause))
+            }
+        }
+    }
+
+    fn emit(&mut self, text: &str) -> Result<()> {
+        self.for_each_sink(|sink| sink.write_token(text))
+    }
+
+    /// Runs `op` on every sink in order. A sink failing with `SinkDisconnected`
+    /// is dropped with a warning; any other error stops the run.
+    fn for_each_sink(
+        &mut self,
+        mut op: impl FnMut(&mut dyn OutputSink) -> Result<()>,
+    ) -> Result<()> {
+        let mut i = 0;
+        while i < self.sinks.len() {
+            match op(self.sinks[i].as_mut()) {
+                Ok(()) => i += 1,
+                Err(e) => {
+                    let Some(disconnected) = e.downcast_ref::<SinkDisconnected>() else {
+                        return Err(e);
+                    };
+                    let message = disconnected.to_string();
+                    self.sinks.remove(i);
+                    self.warn(&message);
+                }
+            }
         }
         Ok(())
     }
+
+    /// Report a diagnostic warning on stderr and, if enabled, the system log.
+    pub fn warn(&mut self, message: &str) {
+        if let Some(writer) = &mut self.writer {
+            if writer
+                .send(OutputMessage::Warn(message.to_string()))
+                .is_ok()
+            {
+                return;
+            }
+        }
+        eprintln!("{}", message);
+        for sink in &mut self.sinks {
+            sink.warn(message);
+        }
+    }
+
+    /// Mark the end of the stream and flush everything. Plain text output just
+    /// gets a closing newline; structured formats emit a final record with `reason`.
+    pub fn finish(&mut self, reason: StopReason) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.send(OutputMessage::Finish(reason))?;
+            return writer.join();
+        }
+
+        // An unterminated tag at the very end is let through as it stands
+        if let Some(tail) = self.think.as_mut().map(ThinkFilter::finish) {
+            if !tail.is_empty() {
+                self.write_answer(&tail)?;
+            }
+        }
+        self.flush()?;
+        self.for_each_sink(|sink| sink.finish(reason))
+    }
+
+    /// Flush any buffered output to every sink.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            return writer.send(OutputMessage::Flush);
+        }
+
+        if let Some(pending) = self.line_buffer.as_mut().map(std::mem::take) {
+            if !pending.is_empty() {
+                self.emit_chunk(&pending)?;
+            }
+        }
+        self.for_each_sink(|sink| sink.flush())
+    }
+}
+
+enum OutputMessage {
+    Token(String),
+    RawBytes(i32, Vec<u8>),
+    Coherence(Coherence),
+    Warn(String),
+    Flush,
+    Finish(StopReason),
+}
+
+/// Handle to the background writer thread. Dropping it drains the queue.
+struct BackgroundWriter {
+    sender: Option<SyncSender<OutputMessage>>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl BackgroundWriter {
+    fn send(&mut self, message: OutputMessage) -> Result<()> {
+        let sent = match &self.sender {
+            Some(sender) => sender.send(message).is_ok(),
+            None => false,
+        };
+        if sent {
+            Ok(())
+        } else {
+            // The thread only hangs up after a write error; surface that error
+            self.join()?;
+            Err(anyhow!("Output writer has already finished"))
+        }
+    }
+
+    /// Close the queue, wait for everything queued to be written, and report
+    /// the first error the writer hit.
+    fn join(&mut self) -> Result<()> {
+        self.sender = None;
+        match self.handle.take() {
+            Some(handle) => handle
+                .join()
+                .map_err(|_| anyhow!("Output writer thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Chunking {
+    /// `--line-buffered`: whole lines or sentences
+    Lines,
+    /// `--tts-chunks`: clauses, split at punctuation or before a conjunction
+    Clauses,
+}
+
+/// Words a clause may be split before, once enough text has built up
+const CLAUSE_CONJUNCTIONS: &[&str] = &[
+    " and ",
+    " but ",
+    " or ",
+    " so ",
+    " yet ",
+    " because ",
+    " while ",
+];
+
+/// Shortest chunk worth cutting at a conjunction; anything shorter sounds choppy
+const MIN_CLAUSE_CHARS: usize = 24;
+
+/// Splits the stream at `--think-tag` delimiters. Tags can arrive split across
+/// tokens, so text that might be the start of one is held back until the next
+/// token settles it.
+struct ThinkFilter {
+    open: String,
+    close: String,
+    hide: bool,
+    inside: bool,
+    pending: String,
+}
+
+impl ThinkFilter {
+    fn new(open: String, close: String, hide: bool) -> Self {
+        Self {
+            open,
+            close,
+            hide,
+            inside: false,
+            pending: String::new(),
+        }
+    }
+
+    /// Writes the reasoning in `text` to stderr and returns the answer part
+    fn route(&mut self, text: &str) -> String {
+        let (reasoning, answer) = self.split(text);
+        self.write_reasoning(&reasoning);
+        answer
+    }
+
+    /// Separates `text` into (reasoning, answer); the tags themselves are dropped
+    fn split(&mut self, text: &str) -> (String, String) {
+        self.pending.push_str(text);
+        let mut reasoning = String::new();
+        let mut answer = String::new();
+        loop {
+            let (tag, out) = if self.inside {
+                (&self.close, &mut reasoning)
+            } else {
+                (&self.open, &mut answer)
+            };
+            if let Some(at) = self.pending.find(tag.as_str()) {
+                out.push_str(&self.pending[..at]);
+                self.pending.drain(..at + tag.len());
+                self.inside = !self.inside;
+                continue;
+            }
+            let keep = partial_tag_len(&self.pending, tag);
+            out.extend(self.pending.drain(..self.pending.len() - keep));
+            return (reasoning, answer);
+        }
+    }
+
+    /// Text still held back at the end of the stream; a half-seen tag was
+    /// never a tag. Only answer text is returned.
+    fn finish(&mut self) -> String {
+        let pending = std::mem::take(&mut self.pending);
+        if self.inside {
+            self.write_reasoning(&pending);
+            String::new()
+        } else {
+            pending
+        }
+    }
+
+    fn write_reasoning(&self, text: &str) {
+        if text.is_empty() || self.hide {
+            return;
+        }
+        let mut stderr = io::stderr();
+        let _ = stderr.write_all(text.as_bytes());
+        let _ = stderr.flush();
+    }
+}
+
+/// Length of the longest suffix of `text` that is a proper prefix of `tag`
+fn partial_tag_len(text: &str, tag: &str) -> usize {
+    (1..tag.len())
+        .rev()
+        .find(|&k| tag.is_char_boundary(k) && text.ends_with(&tag[..k]))
+        .unwrap_or(0)
+}
+
+/// Drop newlines beyond the second in a row; `run` carries the trailing count
+/// from previous tokens. Carriage returns don't break a run.
+fn collapse_newlines(text: &str, run: &mut usize) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' => {
+                *run += 1;
+                if *run <= 2 {
+                    out.push(c);
+                }
+            }
+            '\r' => {
+                if *run <= 2 {
+                    out.push(c);
+                }
+            }
+            _ => {
+                *run = 0;
+                out.push(c);
+            }
+        }
+    }
+    out
+}
+
+/// End of the last complete clause in `text`: just past clause punctuation, or
+/// just before a conjunction that follows enough text
+fn clause_boundary(text: &str) -> Option<usize> {
+    let punctuation = text
+        .rfind(['\n', ',', ';', ':', '.', '!', '?'])
+        .map(|end| end + 1);
+    let conjunction = CLAUSE_CONJUNCTIONS
+        .iter()
+        .filter_map(|word| text.rfind(word))
+        .filter(|&start| start >= MIN_CLAUSE_CHARS)
+        .max();
+    punctuation.max(conjunction)
 }
 
 pub struct TerminalOutput;
@@ -54,35 +554,787 @@ impl TerminalOutput {
     }
 }
 
+impl OutputSink for TerminalOutput {
+    fn write_token(&mut self, text: &str) -> Result<()> {
+        self.write(text)
+    }
+
+    fn finish(&mut self, _reason: StopReason) -> Result<()> {
+        self.write("\n")
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        io::stdout().flush()?;
+        Ok(())
+    }
+}
+
+/// Tuning for the mirrored output file
+#[derive(Clone, Copy, Debug)]
+pub struct FileOptions {
+    /// Tokens between flushes (0 = only on exit)
+    pub flush_interval: usize,
+    /// Start a new numbered file once the current one exceeds this many bytes
+    pub rotate_bytes: Option<u64>,
+    /// Hold back text after the last sentence end and drop it when the stream finishes
+    pub trim_incomplete: bool,
+}
+
+/// Buffered file mirror. Flushing every token keeps the file readable live but
+/// wears SD cards quickly; larger intervals trade durability on a hard crash for
+/// fewer writes. A clean exit always flushes and syncs.
 pub struct FileOutput {
-    file: File,
+    file: BufWriter<File>,
+    path: PathBuf,
+    options: FileOptions,
+    pending: usize,
+    written: u64,
+    rotation: usize,
+    /// Text since the last sentence end, not yet written (`trim_incomplete`)
+    tail: Option<String>,
 }
 
 impl FileOutput {
-    pub fn new(path: &Path) -> Result<Self> {
+    pub fn new(path: &Path, options: FileOptions) -> Result<Self> {
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent)?;
         }
 
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(false)
-            .truncate(true)
-            .open(path)?;
-
-        Ok(Self { file })
+        Ok(Self {
+            file: open_truncated(path)?,
+            path: path.to_path_buf(),
+            options,
+            pending: 0,
+            written: 0,
+            rotation: 0,
+            tail: options.trim_incomplete.then(String::new),
+        })
     }
 
     pub fn write(&mut self, text: &str) -> Result<()> {
+        let Some(tail) = &mut self.tail else {
+            return self.write_through(text);
+        };
+        tail.push_str(text);
+        let end = complete_prefix_len(tail);
+        if end == 0 {
+            return Ok(());
+        }
+        let ready: String = tail.drain(..end).collect();
+        self.write_through(&ready)
+    }
+
+    /// Forget the unfinished sentence at the end of the stream; the terminal
+    /// already showed it, but the saved file ends on a complete one.
+    pub fn drop_incomplete_tail(&mut self) {
+        if let Some(tail) = &mut self.tail {
+            if !tail.trim().is_empty() {
+                debug!(
+                    "Trimmed incomplete trailing text from the output file: {:?}",
+                    tail
+                );
+            }
+            tail.clear();
+        }
+    }
+
+    fn write_through(&mut self, text: &str) -> Result<()> {
+        if let Some(limit) = self.options.rotate_bytes {
+            if self.written > 0 && self.written + text.len() as u64 > limit {
+                self.rotate()?;
+            }
+        }
+
         self.file.write_all(text.as_bytes())?;
+        self.written += text.len() as u64;
+        self.pending += 1;
+        let interval = self.options.flush_interval;
+        if interval > 0 && self.pending >= interval {
+            self.file.flush()?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    /// Flush buffered text and sync it to disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        self.pending = 0;
+        Ok(())
+    }
+
+    /// Close the current file and continue in the next numbered one.
+    fn rotate(&mut self) -> Result<()> {
+        self.flush()?;
+        self.rotation += 1;
+        self.file = open_truncated(&rotated_path(&self.path, self.rotation))?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl OutputSink for FileOutput {
+    fn write_token(&mut self, text: &str) -> Result<()> {
+        self.write(text)
+    }
+
+    fn finish(&mut self, _reason: StopReason) -> Result<()> {
+        self.drop_incomplete_tail();
+        self.write("\n")?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        FileOutput::flush(self)
+    }
+}
+
+/// Length of `text` up to and including its last sentence end (`.`, `!`, `?`
+/// plus any closing quotes or brackets) or newline; 0 if there is none.
+fn complete_prefix_len(text: &str) -> usize {
+    let Some(end) = text.rfind(['\n', '.', '!', '?']) else {
+        return 0;
+    };
+    let rest = &text[end + 1..];
+    let closers = rest
+        .find(|c: char| !matches!(c, '"' | '\'' | ')' | ']' | '\u{201d}' | '\u{2019}'))
+        .unwrap_or(rest.len());
+    end + 1 + closers
+}
+
+fn open_truncated(path: &Path) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(false)
+        .truncate(true)
+        .open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+/// `out.txt` -> `out.1.txt`, `out.2.txt`, ...
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, index),
+    };
+    path.with_file_name(name)
+}
+
+/// Points kept of the coherence trajectory in the stats sidecar
+const COHERENCE_POINTS: usize = 20;
+
+/// One `--output-dir` run: a timestamped text file plus a `.json` stats sidecar
+struct RunSidecar {
+    text_path: PathBuf,
+    stats_path: PathBuf,
+    started_at: String,
+    started: Instant,
+    bytes: u64,
+    coherence: Coherence,
+}
+
+impl RunSidecar {
+    fn new(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let started_at = utc_timestamp(SystemTime::now());
+
+        // Two runs in the same second get a numeric suffix instead of clobbering
+        let mut stem = started_at.replace(':', "-");
+        let mut attempt = 1;
+        while dir.join(format!("{}.txt", stem)).exists() {
+            stem = format!("{}-{}", started_at.replace(':', "-"), attempt);
+            attempt += 1;
+        }
+
+        Ok(Self {
+            text_path: dir.join(format!("{}.txt", stem)),
+            stats_path: dir.join(format!("{}.json", stem)),
+            started_at,
+            started: Instant::now(),
+            bytes: 0,
+            coherence: Coherence::default(),
+        })
+    }
+
+    fn write_stats(&self, reason: StopReason) -> Result<()> {
+        let text_file = self
+            .text_path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // null until the run fills a whole diversity window
+        let coherence = self.coherence.average().map(|average| {
+            serde_json::json!({
+                "average": rounded(f64::from(average), 3),
+                "windows": self.coherence.scores().len(),
+                "trajectory": self
+                    .coherence
+                    .trajectory(COHERENCE_POINTS)
+                    .iter()
+                    .map(|&s| rounded(f64::from(s), 3))
+                    .collect::<Vec<_>>(),
+            })
+        });
+        let stats = serde_json::json!({
+            "started": self.started_at,
+            "text_file": text_file,
+            "stop_reason": reason.as_str(),
+            "elapsed_seconds": rounded(self.started.elapsed().as_secs_f64(), 1),
+            "bytes": self.bytes,
+            "coherence": coherence,
+        });
+        let mut file = BufWriter::new(File::create(&self.stats_path)?);
+        serde_json::to_writer(&mut file, &stats)?;
+        writeln!(file)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Counts the bytes written to the run's text file and writes the stats file
+/// when the stream ends; listed after the file sink.
+impl OutputSink for RunSidecar {
+    fn write_token(&mut self, text: &str) -> Result<()> {
+        self.bytes += text.len() as u64;
+        Ok(())
+    }
+
+    fn record_coherence(&mut self, coherence: &Coherence) -> Result<()> {
+        self.coherence = coherence.clone();
+        Ok(())
+    }
+
+    fn finish(&mut self, reason: StopReason) -> Result<()> {
+        self.bytes += 1;
+        self.write_stats(reason)
+    }
+}
+
+/// `value` to `places` decimals, so the sidecar stays readable
+fn rounded(value: f64, places: i32) -> f64 {
+    let scale = 10f64.powi(places);
+    (value * scale).round() / scale
+}
+
+/// `2024-06-01T12:00:00Z` style UTC timestamp, without pulling in a date crate
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Civil-from-days (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// `--raw-bytes-output`: one line per sampled token, `<token id>\t<hex bytes>`,
+/// exactly as the detokenizer returned them, before any UTF-8 handling. Shows
+/// where multibyte characters split across tokens and where special tokens land.
+pub struct RawBytesOutput {
+    file: BufWriter<File>,
+}
+
+impl RawBytesOutput {
+    pub fn new(path: &Path) -> Result<Self> {
+        let file = open_truncated(path)
+            .with_context(|| format!("Failed to create raw bytes file {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    pub fn write(&mut self, token: i32, bytes: &[u8]) -> Result<()> {
+        writeln!(self.file, "{}\t{}", token, hex_bytes(bytes))?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
         self.file.flush()?;
         Ok(())
     }
 }
 
+impl OutputSink for RawBytesOutput {
+    fn write_token(&mut self, _text: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self, _reason: StopReason) -> Result<()> {
+        RawBytesOutput::flush(self)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        RawBytesOutput::flush(self)
+    }
+
+    fn write_raw_bytes(&mut self, token: i32, bytes: &[u8]) -> Result<()> {
+        self.write(token, bytes)
+    }
+}
+
+/// `--jsonl-file`: the same stream as JSON Lines for archiving and tooling. One
+/// `{"index","text","elapsed_ms"}` record per chunk the other sinks get, then a
+/// closing `{"stop_reason","chunks"}` record.
+pub struct JsonlOutput {
+    file: BufWriter<Box<dyn Write + Send>>,
+    started: Instant,
+    records: usize,
+}
+
+impl JsonlOutput {
+    /// Writes to `path`, or to stdout when it is "-"
+    pub fn new(path: &Path) -> Result<Self> {
+        let file = if path.as_os_str() == "-" {
+            BufWriter::new(Box::new(io::stdout()) as Box<dyn Write + Send>)
+        } else {
+            let file = File::create(path)
+                .with_context(|| format!("Failed to create JSONL file {}", path.display()))?;
+            BufWriter::new(Box::new(file) as Box<dyn Write + Send>)
+        };
+        Ok(Self {
+            file,
+            started: Instant::now(),
+            records: 0,
+        })
+    }
+}
+
+impl OutputSink for JsonlOutput {
+    fn write_token(&mut self, text: &str) -> Result<()> {
+        let record = serde_json::json!({
+            "index": self.records,
+            "text": text,
+            "elapsed_ms": self.started.elapsed().as_millis() as u64,
+        });
+        writeln!(self.file, "{}", record)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self, reason: StopReason) -> Result<()> {
+        let record = serde_json::json!({
+            "stop_reason": reason.as_str(),
+            "chunks": self.records,
+        });
+        writeln!(self.file, "{}", record)?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// `e2 80` style lowercase hex, space separated
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Streams tokens into a named pipe for other local processes. Writes go straight
+/// to the pipe, unbuffered, so readers see each token as it is generated.
+pub struct FifoOutput {
+    pipe: File,
+    path: PathBuf,
+    /// Drop the FIFO instead of failing when the reader goes away (EPIPE)
+    continue_on_disconnect: bool,
+}
+
+impl FifoOutput {
+    /// Create the FIFO if needed and open it for writing. Opening blocks until a
+    /// reader connects.
+    pub fn open(path: &Path, continue_on_disconnect: bool) -> Result<Self> {
+        match std::fs::metadata(path) {
+            Ok(meta) if !meta.file_type().is_fifo() => {
+                anyhow::bail!("{} exists and is not a named pipe", path.display());
+            }
+            Ok(_) => {}
+            Err(_) => mkfifo(path)?,
+        }
+
+        info!("Waiting for a reader on FIFO {}...", path.display());
+        let pipe = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open FIFO: {}", path.display()))?;
+
+        Ok(Self {
+            pipe,
+            path: path.to_path_buf(),
+            continue_on_disconnect,
+        })
+    }
+
+    pub fn write(&mut self, text: &str) -> Result<()> {
+        self.pipe.write_all(text.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl OutputSink for FifoOutput {
+    fn write_token(&mut self, text: &str) -> Result<()> {
+        let result = self.write(text);
+        match result {
+            Err(e) if self.continue_on_disconnect && is_broken_pipe(&e) => {
+                Err(SinkDisconnected(format!(
+                    "FIFO reader on {} disconnected; continuing without it",
+                    self.path.display()
+                ))
+                .into())
+            }
+            other => other,
+        }
+    }
+
+    fn finish(&mut self, _reason: StopReason) -> Result<()> {
+        self.write_token("\n")
+    }
+}
+
+fn mkfifo(path: &Path) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid FIFO path: {}", path.display()))?;
+    // SAFETY: c_path is a valid NUL-terminated string for the duration of the call
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to create FIFO: {}", path.display()));
+    }
+    Ok(())
+}
+
+fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// Sends generated text to the system log (journald picks this up) one line at a
+/// time. Tokens are buffered until a newline so lines are never split mid-word.
+pub struct SyslogOutput {
+    logger: Logger<LoggerBackend, Formatter3164>,
+    line: String,
+}
+
+impl SyslogOutput {
+    pub fn new() -> Result<Self> {
+        let formatter = Formatter3164 {
+            facility: Facility::LOG_USER,
+            hostname: None,
+            process: env!("CARGO_PKG_NAME").into(),
+            pid: std::process::id(),
+        };
+        // syslog's error type isn't Sync, so it can't convert into anyhow directly
+        let logger =
+            syslog::unix(formatter).map_err(|e| anyhow!("Failed to connect to syslog: {}", e))?;
+
+        Ok(Self {
+            logger,
+            line: String::new(),
+        })
+    }
+
+    pub fn write(&mut self, text: &str) -> Result<()> {
+        self.line.push_str(text);
+        while let Some(end) = self.line.find('\n') {
+            let line: String = self.line.drain(..=end).collect();
+            self.info(line.trim_end())?;
+        }
+        Ok(())
+    }
+
+    /// Log any partial line still buffered.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.line.trim().is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.info(line.trim_end())?;
+        }
+        self.line.clear();
+        Ok(())
+    }
+
+    fn info(&mut self, line: &str) -> Result<()> {
+        if line.is_empty() {
+            return Ok(());
+        }
+        self.logger
+            .info(line)
+            .map_err(|e| anyhow!("Failed to write to syslog: {}", e))
+    }
+
+    /// Explicit end marker so journal readers don't have to infer it
+    pub fn end_of_stream(&mut self, reason: StopReason) -> Result<()> {
+        self.logger
+            .notice(format!("end of stream: {}", reason.as_str()))
+            .map_err(|e| anyhow!("Failed to write to syslog: {}", e))
+    }
+
+    fn warning(&mut self, message: &str) -> Result<()> {
+        self.logger
+            .warning(message)
+            .map_err(|e| anyhow!("Failed to write to syslog: {}", e))
+    }
+}
+
+impl OutputSink for SyslogOutput {
+    fn write_token(&mut self, text: &str) -> Result<()> {
+        self.write(text)
+    }
+
+    fn finish(&mut self, reason: StopReason) -> Result<()> {
+        self.write("\n")?;
+        self.end_of_stream(reason)?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        SyslogOutput::flush(self)
+    }
+
+    fn warn(&mut self, message: &str) {
+        let _ = self.warning(message.trim());
+    }
+}
+
+/// Test sink that keeps everything written, so output behavior (buffering,
+/// anchors, stop handling) can be asserted without capturing stdout. Clones
+/// share the same log: keep one and hand the other to `OutputTarget`.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct MemoryOutput {
+    log: std::sync::Arc<std::sync::Mutex<MemoryLog>>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MemoryLog {
+    tokens: Vec<String>,
+    finished: Option<StopReason>,
+}
+
+#[cfg(test)]
+impl MemoryOutput {
+    /// Each chunk as it reached the sink
+    pub(crate) fn tokens(&self) -> Vec<String> {
+        self.log.lock().unwrap().tokens.clone()
+    }
+
+    /// Everything written, concatenated
+    pub(crate) fn text(&self) -> String {
+        self.log.lock().unwrap().tokens.concat()
+    }
+
+    pub(crate) fn finished(&self) -> Option<StopReason> {
+        self.log.lock().unwrap().finished
+    }
+}
+
+#[cfg(test)]
+impl OutputSink for MemoryOutput {
+    fn write_token(&mut self, text: &str) -> Result<()> {
+        self.log.lock().unwrap().tokens.push(text.to_string());
+        Ok(())
+    }
+
+    fn finish(&mut self, reason: StopReason) -> Result<()> {
+        self.log.lock().unwrap().finished = Some(reason);
+        Ok(())
+    }
+}
+
 fn has_spi_device() -> bool {
     ["/dev/spidev0.0", "/dev/spidev0.1", "/dev/fb1"]
         .iter()
         .any(|p| Path::new(p).exists())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Disconnects after accepting `limit` tokens
+    struct FlakySink {
+        inner: MemoryOutput,
+        limit: usize,
+    }
+
+    impl OutputSink for FlakySink {
+        fn write_token(&mut self, text: &str) -> Result<()> {
+            if self.inner.tokens().len() >= self.limit {
+                return Err(SinkDisconnected("gone".to_string()).into());
+            }
+            self.inner.write_token(text)
+        }
+
+        fn finish(&mut self, reason: StopReason) -> Result<()> {
+            self.inner.finish(reason)
+        }
+    }
+
+    #[test]
+    fn tokens_fan_out_and_disconnected_sinks_drop_out() {
+        let steady = MemoryOutput::default();
+        let flaky = MemoryOutput::default();
+        let mut output = OutputTarget::with_sinks(vec![
+            Box::new(FlakySink {
+                inner: flaky.clone(),
+                limit: 1,
+            }),
+            Box::new(steady.clone()),
+        ]);
+        output.write_token("a").unwrap();
+        output.write_token("b").unwrap();
+        output.finish(StopReason::TokenLimit).unwrap();
+        assert_eq!(steady.tokens(), ["a", "b"]);
+        assert_eq!(steady.finished(), Some(StopReason::TokenLimit));
+        assert_eq!(flaky.tokens(), ["a"]);
+        assert_eq!(flaky.finished(), None);
+    }
+
+    #[test]
+    fn line_buffering_emits_whole_sentences() {
+        let memory = MemoryOutput::default();
+        let mut output =
+            OutputTarget::with_sinks(vec![Box::new(memory.clone())]).with_line_buffering(true);
+        for token in ["The", " sea", " is", " calm", ".", " Then", " a"] {
+            output.write_token(token).unwrap();
+        }
+        assert_eq!(memory.tokens(), ["The sea is calm."]);
+        output.finish(StopReason::Interrupted).unwrap();
+        assert_eq!(memory.text(), "The sea is calm. Then a");
+    }
+
+    #[test]
+    fn clause_chunks_become_trimmed_lines() {
+        let memory = MemoryOutput::default();
+        let mut output =
+            OutputTarget::with_sinks(vec![Box::new(memory.clone())]).with_clause_chunking(true);
+        for token in ["First", " clause,", " second", " one;", " tail"] {
+            output.write_token(token).unwrap();
+        }
+        output.flush().unwrap();
+        assert_eq!(
+            memory.tokens(),
+            ["First clause,\n", "second one;\n", "tail\n"]
+        );
+    }
+
+    #[test]
+    fn think_tags_split_across_tokens_are_routed() {
+        let mut think = ThinkFilter::new("<think>".into(), "</think>".into(), true);
+        let mut reasoning = String::new();
+        let mut answer = String::new();
+        for token in ["I <th", "ink>hmm, <", "/thi", "nk>am <", "b>here"] {
+            let (r, a) = think.split(token);
+            reasoning.push_str(&r);
+            answer.push_str(&a);
+        }
+        assert_eq!(reasoning, "hmm, ");
+        assert_eq!(answer, "I am <b>here");
+        assert_eq!(think.finish(), "");
+
+        // Only answer text reaches the sinks, and a dangling tag prefix is
+        // released at the end
+        let memory = MemoryOutput::default();
+        let mut output = OutputTarget::with_sinks(vec![Box::new(memory.clone())])
+            .with_think_tags(Some(("<think>".into(), "</think>".into())), true);
+        for token in ["<think>", "plan", "</think>", "Hello", " <thi"] {
+            output.write_token(token).unwrap();
+        }
+        output.finish(StopReason::TokenLimit).unwrap();
+        assert_eq!(memory.text(), "Hello <thi");
+    }
+
+    #[test]
+    fn blank_line_runs_collapse_across_tokens() {
+        let mut run = 0;
+        let out: String = ["One.\n", "\n", "\n\n", "Two.\n\n\nThree"]
+            .iter()
+            .map(|t| collapse_newlines(t, &mut run))
+            .collect();
+        assert_eq!(out, "One.\n\nTwo.\n\nThree");
+        assert_eq!(run, 0);
+    }
+
+    #[test]
+    fn complete_prefix_ends_after_sentence_and_closers() {
+        assert_eq!(complete_prefix_len("no end yet"), 0);
+        assert_eq!(complete_prefix_len("Done. And th"), 5);
+        assert_eq!(
+            complete_prefix_len("He said \u{201c}stop.\u{201d} Then"),
+            19
+        );
+        assert_eq!(complete_prefix_len("line\npartial"), 5);
+    }
+
+    #[test]
+    fn hex_bytes_keeps_split_multibyte_sequences_visible() {
+        assert_eq!(hex_bytes("\u{2014}".as_bytes()), "e2 80 94");
+        assert_eq!(hex_bytes(&[0xe2, 0x80]), "e2 80");
+        assert_eq!(hex_bytes(&[]), "");
+    }
+
+    #[test]
+    fn clause_boundary_cuts_after_punctuation() {
+        assert_eq!(clause_boundary("The lamp flickered, and"), Some(19));
+        assert_eq!(clause_boundary("no break yet"), None);
+    }
+
+    #[test]
+    fn utc_timestamp_formats_known_instants() {
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let later = UNIX_EPOCH + Duration::from_secs(1_717_243_200);
+        assert_eq!(utc_timestamp(later), "2024-06-01T12:00:00Z");
+    }
+
+    #[test]
+    fn clause_boundary_cuts_before_long_conjunction() {
+        let text = "the corridor kept stretching on and ";
+        assert_eq!(clause_boundary(text), text.find(" and "));
+        assert_eq!(clause_boundary("dust and "), None);
+    }
+
+    #[test]
+    fn run_stats_are_valid_json() {
+        let dir = std::env::temp_dir().join(format!("ooc-\"runs\"-{}", std::process::id()));
+        let mut run = RunSidecar::new(&dir).unwrap();
+        run.write_token("a \"quoted\" line").unwrap();
+        run.finish(StopReason::TokenLimit).unwrap();
+
+        let stats: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&run.stats_path).unwrap()).unwrap();
+        assert_eq!(stats["stop_reason"], "token_limit");
+        // Plus the newline the file sink ends on
+        assert_eq!(stats["bytes"], 16);
+        assert!(stats["coherence"].is_null());
+        assert!(stats["text_file"].as_str().unwrap().ends_with(".txt"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::{Context, Result};
+use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::LlamaToken;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{info, info_span};
+
+use crate::generator::{self, GenerationConfig, Generator, SamplingConfig, StopReason};
+use crate::llm::{LLMSetup, LlamaBatchWrapper};
+use crate::output::OutputTarget;
+
+/// One monologue in a `--parallel` batch: KV sequence `seq` of the shared context
+struct Stream {
+    seq: i32,
+    sampler: LlamaSampler,
+    output: OutputTarget,
+    n_past: usize,
+    generated_tokens: usize,
+    /// Sampled but not yet decoded
+    pending: Option<LlamaToken>,
+    done: Option<StopReason>,
+}
+
+/// `--parallel N`: runs one independent monologue per output, each on its own
+/// KV sequence with its own seed and sampler, advancing all of them with a
+/// single batched decode per step.
+///
+/// `generator` must hold the decoded prompt on sequence 0 of a context created
+/// with `LLMSetup::create_parallel_context`; the prompt is copied to the other
+/// sequences rather than decoded again. Anchors and the loop guard are
+/// single-sequence features and don't run here.
+pub fn run(
+    llm_setup: &LLMSetup,
+    generator: &mut Generator,
+    cfg: &GenerationConfig,
+    sampling: &SamplingConfig,
+    outputs: Vec<OutputTarget>,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let _span = info_span!("parallel", streams = outputs.len()).entered();
+    let panic_threshold = (cfg.context_size as f32 * 0.95) as usize;
+    let prompt_len = generator.n_past();
+    let preamble: Vec<LlamaToken> = generator.preamble().collect();
+
+    let mut streams = Vec::with_capacity(outputs.len());
+    for (i, output) in outputs.into_iter().enumerate() {
+        let seq = i32::try_from(i).context("Too many parallel streams")?;
+        if seq > 0 {
+            generator
+                .context_mut()
+                .copy_kv_cache_seq(0, seq, None, None)
+                .context("Failed to copy the prompt to a parallel sequence")?;
+        }
+        let seed = match sampling.seed {
+            Some(base) => base.wrapping_add(i as u32),
+            None => generator::resolve_seed(None),
+        };
+        info!("Stream {} seed: {}", i + 1, seed);
+        let mut sampler = generator::build_sampler(llm_setup, sampling, cfg.context_size, seed)?;
+        sampler.accept_many(preamble.iter().copied());
+        streams.push(Stream {
+            seq,
+            sampler,
+            output,
+            n_past: prompt_len,
+            generated_tokens: 0,
+            pending: None,
+            done: None,
+        });
+    }
+
+    let started = Instant::now();
+    let deadline = cfg
+        .max_seconds
+        .map(|secs| started + Duration::from_secs(secs));
+    // Batch index holding each stream's logits; the first step shares the prompt's
+    let mut logits_at: Vec<Option<i32>> = vec![None; streams.len()];
+
+    loop {
+        // Streams grow in lockstep, so one exhausting its share ends them all
+        if streams
+            .iter()
+            .any(|s| s.done.is_none() && s.n_past >= panic_threshold)
+        {
+            for stream in streams.iter_mut() {
+                stream.output.warn("\n\nWARNING: Context window exhausted!");
+                let _ = stream.output.finish(StopReason::ContextExhausted);
+            }
+            panic!("Context overflow - terminating.");
+        }
+
+        let interrupted = stop.load(Ordering::Relaxed);
+        let timed_out = deadline.is_some_and(|d| Instant::now() >= d);
+
+        for (stream, logits) in streams.iter_mut().zip(&logits_at) {
+            if stream.done.is_some() {
+                continue;
+            }
+            if interrupted {
+                stream.done = Some(StopReason::Interrupted);
+                continue;
+            }
+            if timed_out {
+                stream.done = Some(StopReason::TimeLimit);
+                continue;
+            }
+            if cfg
+                .max_tokens
+                .is_some_and(|limit| stream.generated_tokens >= limit)
+            {
+                stream.done = Some(StopReason::TokenLimit);
+                continue;
+            }
+
+            let candidates: Vec<_> = match logits {
+                Some(index) => generator.context_mut().candidates_ith(*index).collect(),
+                None => generator.candidates().collect(),
+            };
+            let mut token_data_array = LlamaTokenDataArray::from_iter(candidates, false);
+            token_data_array.apply_sampler(&stream.sampler);
+            let token = token_data_array
+                .selected_token()
+                .context("Sampler failed to select a token")?;
+            stream.sampler.accept(token);
+
+            let text = if llm_setup.is_control_token(token) {
+                if !cfg.show_special {
+                    stream.done = Some(StopReason::SpecialToken);
+                    continue;
+                }
+                llm_setup.special_token_text(token)?
+            } else {
+                llm_setup.decode_token(token)?
+            };
+            stream.output.write_token(&text)?;
+            stream.generated_tokens += 1;
+            stream.pending = Some(token);
+        }
+
+        // One decode advances every live stream by a token
+        let live = streams.iter().filter(|s| s.pending.is_some()).count();
+        if live == 0 {
+            break;
+        }
+        let mut batch = LlamaBatchWrapper::new(live)?;
+        let mut batch_index = 0;
+        for (stream, logits) in streams.iter_mut().zip(logits_at.iter_mut()) {
+            let Some(token) = stream.pending.take() else {
+                continue;
+            };
+            let pos = i32::try_from(stream.n_past)
+                .context("KV position exceeds i32::MAX; reduce --context-size")?;
+            batch.get_mut().add(token, pos, &[stream.seq], true)?;
+            stream.n_past += 1;
+            *logits = Some(batch_index);
+            batch_index += 1;
+        }
+        generator
+            .context_mut()
+            .decode(batch.get_mut())
+            .context("Failed to decode parallel batch")?;
+    }
+
+    let elapsed = started.elapsed().as_secs_f32();
+    let mut total = 0;
+    for (i, stream) in streams.iter_mut().enumerate() {
+        let reason = stream.done.unwrap_or(StopReason::Interrupted);
+        stream.output.finish(reason)?;
+        info!(
+            "Stream {}: {} tokens, stopped: {}",
+            i + 1,
+            stream.generated_tokens,
+            reason.as_str()
+        );
+        total += stream.generated_tokens;
+    }
+    info!(
+        "Generated {} tokens across {} streams in {:.1}s ({:.2} tok/s).",
+        total,
+        streams.len(),
+        elapsed,
+        total as f32 / elapsed.max(f32::EPSILON)
+    );
+    Ok(())
+}
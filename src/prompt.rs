@@ -0,0 +1,94 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Resolves the system prompt text.
+///
+/// Precedence: inline `--prompt` string, then the prompt files. Multiple files are
+/// read in order and joined with a newline; `-` reads that layer from stdin.
+/// Files that are together empty or whitespace-only are rejected rather than
+/// silently leaving the model with a bare template.
+pub fn load_system_prompt(inline: Option<&str>, prompt_files: &[PathBuf]) -> Result<String> {
+    if let Some(text) = inline {
+        return Ok(text.to_string());
+    }
+
+    let layers = prompt_files
+        .iter()
+        .map(|path| read_prompt_file(path))
+        .collect::<Result<Vec<_>>>()?;
+    let text = layers.join("\n");
+
+    if !prompt_files.is_empty() && text.trim().is_empty() {
+        anyhow::bail!(
+            "Prompt file {} is empty; it produced no prompt text",
+            display_paths(prompt_files)
+        );
+    }
+    Ok(text)
+}
+
+/// Reads `--prime-with` narration (`-` reads stdin). It is decoded after the
+/// prompt as plain continuation text, never wrapped in a chat turn.
+pub fn load_prime_text(path: &Path) -> Result<String> {
+    read_prompt_file(path)
+}
+
+/// Reads a `--raw-prompt` file (`-` reads stdin), used verbatim as the whole prompt.
+pub fn load_raw_prompt(path: &Path) -> Result<String> {
+    let text = read_prompt_file(path)?;
+    if text.trim().is_empty() {
+        anyhow::bail!(
+            "Raw prompt file {} is empty; it produced no prompt text",
+            path.display()
+        );
+    }
+    Ok(text)
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn read_prompt_file(prompt_file: &Path) -> Result<String> {
+    if prompt_file == Path::new("-") {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .context("Failed to read system prompt from stdin")?;
+        return Ok(text);
+    }
+
+    fs::read_to_string(prompt_file)
+        .with_context(|| format!("Failed to read prompt file: {}", prompt_file.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_prompt_files_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("ooc-prompt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.txt");
+        let blank = dir.join("blank.txt");
+        fs::write(&empty, "").unwrap();
+        fs::write(&blank, " \n\t\n").unwrap();
+
+        let err = load_system_prompt(None, &[empty.clone(), blank.clone()]).unwrap_err();
+        assert!(err.to_string().contains("produced no prompt text"));
+        assert!(load_raw_prompt(&empty).is_err());
+        assert!(load_raw_prompt(&blank).is_err());
+        // An inline prompt still wins, and no files means the built-in default
+        assert_eq!(load_system_prompt(Some("hi"), &[empty]).unwrap(), "hi");
+        assert_eq!(load_system_prompt(None, &[]).unwrap(), "");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, info_span, warn};
+
+use crate::generator::{
+    self, GenerationConfig, Generator, SamplingConfig, StopReason, TokenDecision,
+};
+use crate::llm::LLMSetup;
+use crate::output::OutputTarget;
+use crate::triggers::KeywordTriggers;
+
+/// Positions kept free past the next decode so a reply never fills the window
+const REPLY_HEADROOM: usize = 8;
+
+/// Typed at the prompt to leave, besides end of input
+const EXIT_COMMANDS: &[&str] = &["/exit", "/quit"];
+
+/// Decodes the system prompt as a lone ChatML system message, the fixed start
+/// of an `--interactive` conversation
+pub fn start<'c, 'm>(
+    llm_setup: &LLMSetup,
+    context: &'c mut LlamaContext<'m>,
+    system_prompt: &str,
+    cfg: &GenerationConfig,
+) -> Result<Generator<'c, 'm>> {
+    let prompt_tokens = llm_setup.tokenize(
+        &generator::chat_turn("system", system_prompt.trim()),
+        cfg.add_bos,
+    )?;
+    if prompt_tokens.len() * 2 > cfg.context_size {
+        anyhow::bail!(
+            "System prompt ({} tokens) leaves too little of the {}-token context for a conversation; shorten it or raise --context-size",
+            prompt_tokens.len(),
+            cfg.context_size
+        );
+    }
+    Generator::new(context, &prompt_tokens).context("Failed to decode system prompt")
+}
+
+/// `--interactive`: a turn-based chat instead of the monologue. Each line read
+/// from stdin becomes a ChatML user message and the model replies until it
+/// ends its turn, hits `--stop-at` or `--max-tokens`, or is interrupted.
+///
+/// `generator` comes from `start`, with the system prompt pinned at the front;
+/// when the conversation outgrows the window the older half of it is shifted
+/// out rather than panicking. Anchors and the loop guard belong to the
+/// monologue and don't run here.
+pub fn run(
+    llm_setup: &LLMSetup,
+    generator: &mut Generator,
+    cfg: &GenerationConfig,
+    sampling: &SamplingConfig,
+    output: &mut OutputTarget,
+    stop_at: &[String],
+    stop: &AtomicBool,
+) -> Result<()> {
+    let _span = info_span!("interactive").entered();
+    let seed = generator::resolve_seed(sampling.seed);
+    let mut sampler = generator::build_sampler(llm_setup, sampling, cfg.context_size, seed)?;
+    sampler.accept_many(generator.preamble());
+    let turn_end = llm_setup.tokenize("<|im_end|>\n", false)?;
+
+    let stdin = io::stdin();
+    let at_terminal = stdin.is_terminal();
+    if !cfg.quiet {
+        eprintln!(
+            "Type a message and press Enter. {} or end of input quits; Ctrl-C cuts a reply short.",
+            EXIT_COMMANDS.join(" or ")
+        );
+    }
+
+    let mut line = String::new();
+    loop {
+        if at_terminal {
+            eprint!("\n> ");
+            io::stderr().flush()?;
+        }
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        let message = line.trim();
+        if message.is_empty() {
+            continue;
+        }
+        if EXIT_COMMANDS.contains(&message) {
+            break;
+        }
+
+        let turn = format!(
+            "{}<|im_start|>assistant\n",
+            generator::chat_turn("user", message)
+        );
+        let turn_tokens = llm_setup.tokenize(&turn, false)?;
+        if !make_room(generator, cfg.context_size, turn_tokens.len())? {
+            warn!(
+                "Message ({} tokens) doesn't fit the context even after dropping the history; send a shorter one.",
+                turn_tokens.len()
+            );
+            continue;
+        }
+        generator
+            .push(&turn_tokens)
+            .context("Failed to decode user message")?;
+        sampler.accept_many(turn_tokens.iter().copied());
+
+        let reason = reply(
+            llm_setup,
+            generator,
+            &mut sampler,
+            cfg,
+            output,
+            stop_at,
+            stop,
+        )?;
+        output.write_token("\n")?;
+        output.flush()?;
+        debug!("Reply ended: {}", reason.as_str());
+        if reason == StopReason::Interrupted {
+            stop.store(false, Ordering::Relaxed);
+        }
+
+        // Close the assistant turn whichever way it ended
+        if make_room(generator, cfg.context_size, turn_end.len())? {
+            generator
+                .push(&turn_end)
+                .context("Failed to decode end of turn")?;
+            sampler.accept_many(turn_end.iter().copied());
+        }
+    }
+
+    output.finish(StopReason::UserRequested)
+}
+
+/// Streams one assistant reply to `output`
+fn reply(
+    llm_setup: &LLMSetup,
+    generator: &mut Generator,
+    sampler: &mut LlamaSampler,
+    cfg: &GenerationConfig,
+    output: &mut OutputTarget,
+    stop_at: &[String],
+    stop: &AtomicBool,
+) -> Result<StopReason> {
+    let mut triggers = KeywordTriggers::new(stop_at, &[]);
+    let mut generated = 0usize;
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(StopReason::Interrupted);
+        }
+        if cfg.max_tokens.is_some_and(|max| generated >= max) {
+            return Ok(StopReason::TokenLimit);
+        }
+
+        let mut candidates = LlamaTokenDataArray::from_iter(generator.candidates(), false);
+        candidates.apply_sampler(sampler);
+        let token = candidates
+            .selected_token()
+            .context("Sampler failed to select a token")?;
+        sampler.accept(token);
+        if llm_setup.is_eog_token(token) {
+            return Ok(StopReason::EndOfSequence);
+        }
+
+        let text = llm_setup.decode_token(token)?;
+        output.write_token(&text)?;
+        generated += 1;
+
+        if !make_room(generator, cfg.context_size, 1)? {
+            return Ok(StopReason::ContextExhausted);
+        }
+        generator
+            .push(&[token])
+            .context("Failed to decode reply token")?;
+        if triggers.decide(&text) == TokenDecision::Stop {
+            return Ok(StopReason::UserRequested);
+        }
+    }
+}
+
+/// Ensures `needed` more tokens (plus headroom) fit by shifting out the older
+/// half of the conversation, or at least as much as is missing. Returns false
+/// when even an empty history isn't enough.
+fn make_room(generator: &mut Generator, context_size: usize, needed: usize) -> Result<bool> {
+    let needed = needed + REPLY_HEADROOM;
+    let free = context_size.saturating_sub(generator.n_past());
+    if free >= needed {
+        return Ok(true);
+    }
+    let history = generator.n_past() - generator.prompt_len();
+    let dropped = generator.shift((needed - free).max(history / 2))?;
+    if dropped > 0 {
+        debug!("Context full; shifted out {} tokens of history", dropped);
+    }
+    Ok(context_size.saturating_sub(generator.n_past()) >= needed)
+}
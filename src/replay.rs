@@ -0,0 +1,39 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::info;
+
+use crate::generator::StopReason;
+use crate::output::OutputTarget;
+
+/// Re-emits a recorded transcript (such as an earlier `--output-file` mirror)
+/// through `output` without loading a model. The text is fed back in word-sized
+/// pieces so `--delay-ms` pacing and line/clause buffering behave like a live run.
+pub fn run(path: &Path, output: &mut OutputTarget, stop: &AtomicBool) -> Result<()> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read replay file: {}", path.display()))?;
+
+    info!("Replaying {} (no model loaded)", path.display());
+
+    let mut pieces = 0usize;
+    let mut reason = StopReason::EndOfReplay;
+    for piece in text.split_inclusive(char::is_whitespace) {
+        if stop.load(Ordering::Relaxed) {
+            reason = StopReason::Interrupted;
+            break;
+        }
+        output.write_token(piece)?;
+        pieces += 1;
+    }
+
+    output.finish(reason)?;
+
+    match reason {
+        StopReason::Interrupted => info!("Interrupted."),
+        _ => info!("Replay finished."),
+    }
+    info!("Replayed {} pieces ({} bytes).", pieces, text.len());
+
+    Ok(())
+}
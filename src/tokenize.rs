@@ -0,0 +1,36 @@
+use anyhow::Result;
+
+use crate::llm::LLMSetup;
+
+/// Prints each token of `text` with its ID and decoded form, then the total
+/// against the configured context size
+pub fn run(llm_setup: &LLMSetup, text: &str, add_bos: bool, context_size: usize) -> Result<()> {
+    let tokens = llm_setup.tokenize(text, add_bos)?;
+
+    for (i, token) in tokens.iter().enumerate() {
+        let piece = if llm_setup.is_control_token(*token) {
+            llm_setup.special_token_text(*token)
+        } else {
+            llm_setup.decode_token(*token)
+        }
+        .unwrap_or_else(|_| "<undecodable>".to_string());
+        println!("{:>5}  {:>6}  {:?}", i, token.0, piece);
+    }
+
+    println!("\nTotal tokens: {}", tokens.len());
+    if tokens.len() >= context_size {
+        println!(
+            "Exceeds context window ({} tokens) by {}.",
+            context_size,
+            tokens.len() - context_size + 1
+        );
+    } else {
+        println!(
+            "Context window: {} tokens ({} left for generation).",
+            context_size,
+            context_size - tokens.len()
+        );
+    }
+
+    Ok(())
+}
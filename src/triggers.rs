@@ -0,0 +1,89 @@
+use crate::generator::TokenDecision;
+
+/// `--stop-at` / `--anchor-on` keyword watcher, used as the generation loop's
+/// token hook. Keeps just enough trailing text to match keywords split
+/// across tokens.
+pub struct KeywordTriggers {
+    stop_at: Vec<String>,
+    anchor_on: Vec<String>,
+    tail: String,
+    /// Longest keyword in chars; the tail never needs more than this
+    window: usize,
+}
+
+impl KeywordTriggers {
+    pub fn new(stop_at: &[String], anchor_on: &[String]) -> Self {
+        let keep = |words: &[String]| -> Vec<String> {
+            words.iter().filter(|w| !w.is_empty()).cloned().collect()
+        };
+        let stop_at = keep(stop_at);
+        let anchor_on = keep(anchor_on);
+        let window = stop_at
+            .iter()
+            .chain(&anchor_on)
+            .map(|w| w.chars().count())
+            .max()
+            .unwrap_or(0);
+        Self {
+            stop_at,
+            anchor_on,
+            tail: String::new(),
+            window,
+        }
+    }
+
+    pub fn decide(&mut self, token_text: &str) -> TokenDecision {
+        if self.window == 0 {
+            return TokenDecision::Continue;
+        }
+
+        self.tail.push_str(token_text);
+        let decision = if self.stop_at.iter().any(|w| self.tail.contains(w.as_str())) {
+            TokenDecision::Stop
+        } else if self
+            .anchor_on
+            .iter()
+            .any(|w| self.tail.contains(w.as_str()))
+        {
+            TokenDecision::InjectAnchor
+        } else {
+            TokenDecision::Continue
+        };
+
+        if decision == TokenDecision::Continue {
+            let excess = self.tail.chars().count().saturating_sub(self.window);
+            if let Some((cut, _)) = self.tail.char_indices().nth(excess) {
+                self.tail.drain(..cut);
+            }
+        } else {
+            // Each occurrence fires once
+            self.tail.clear();
+        }
+        decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(list: &[&str]) -> Vec<String> {
+        list.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn keywords_match_across_token_boundaries() {
+        let mut triggers = KeywordTriggers::new(&words(&["the end"]), &words(&["again"]));
+        assert_eq!(triggers.decide("I say it ag"), TokenDecision::Continue);
+        assert_eq!(triggers.decide("ain"), TokenDecision::InjectAnchor);
+        assert_eq!(triggers.decide(" and"), TokenDecision::Continue);
+        assert_eq!(triggers.decide(" the e"), TokenDecision::Continue);
+        assert_eq!(triggers.decide("nd."), TokenDecision::Stop);
+    }
+
+    #[test]
+    fn no_keywords_always_continue() {
+        let mut triggers = KeywordTriggers::new(&[], &words(&[""]));
+        assert_eq!(triggers.decide("anything"), TokenDecision::Continue);
+    }
+}